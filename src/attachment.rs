@@ -0,0 +1,150 @@
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// VK accepts up to 10 attachments in a message.
+pub const MAX_ATTACHMENTS: usize = 10;
+
+/// Attachment of a board comment, like
+/// `{"type": "photo", "photo": {"id": 1, "owner_id": -2, "access_key": "k"}}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Attachment {
+    Photo {
+        photo: Media,
+    },
+    Video {
+        video: Media,
+    },
+    Doc {
+        doc: Media,
+    },
+    Audio {
+        audio: Media,
+    },
+    Link {
+        link: Link,
+    },
+    Poll {
+        poll: Media,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Media {
+    pub id: i64,
+    pub owner_id: i64,
+    pub access_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub url: String,
+}
+
+impl Media {
+    fn attachment(&self, kind: &str) -> String {
+        match &self.access_key {
+            Some(key) => format!("{}{}_{}_{}", kind, self.owner_id, self.id, key),
+            None => format!("{}{}_{}", kind, self.owner_id, self.id),
+        }
+    }
+
+    fn link(&self, kind: &str) -> String {
+        format!("https://vk.com/{}{}_{}", kind, self.owner_id, self.id)
+    }
+}
+
+impl Attachment {
+    fn media(&self) -> Option<(&'static str, &Media)> {
+        match self {
+            Attachment::Photo { photo } => Some(("photo", photo)),
+            Attachment::Video { video } => Some(("video", video)),
+            Attachment::Doc { doc } => Some(("doc", doc)),
+            Attachment::Audio { audio } => Some(("audio", audio)),
+            Attachment::Poll { poll } => Some(("poll", poll)),
+            Attachment::Link { .. } | Attachment::Unknown => None,
+        }
+    }
+
+    /// Attachment for `messages.send`, like `photo-2_1_k`, if it can be
+    /// reposted.
+    pub fn attachment(&self) -> Option<String> {
+        self.media().map(|(kind, m)| m.attachment(kind))
+    }
+
+    /// Link to add to the text instead.
+    pub fn link(&self) -> Option<String> {
+        match self {
+            Attachment::Link { link } => Some(link.url.clone()),
+            _ => self.media().map(|(kind, m)| m.link(kind)),
+        }
+    }
+}
+
+/// Parses attachments one by one, so that a broken one becomes `Unknown`
+/// instead of failing the whole post.
+pub fn lenient<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Attachment>, D::Error> {
+    let values = Vec::<Value>::deserialize(d)?;
+    let attachments = values
+        .into_iter()
+        .map(|v| {
+            serde_json::from_value(v).unwrap_or_else(|e| {
+                warn!("can't parse attachment: {}", e);
+                Attachment::Unknown
+            })
+        })
+        .collect();
+    Ok(attachments)
+}
+
+/// Splits attachments into the `attachment` parameter of a message and
+/// links for the text, for links and attachments over the limit.
+pub fn repost(attachments: &[Attachment]) -> (Option<String>, Vec<String>) {
+    let (media, other): (Vec<&Attachment>, Vec<&Attachment>) =
+        attachments.iter().partition(|a| a.attachment().is_some());
+    let (media, rest) = media.split_at(media.len().min(MAX_ATTACHMENTS));
+    let attachment: Vec<String> = media.iter().filter_map(|a| a.attachment()).collect();
+    let links = rest
+        .iter()
+        .chain(other.iter())
+        .filter_map(|a| a.link())
+        .collect();
+    let attachment = Some(attachment.join(",")).filter(|a| !a.is_empty());
+    (attachment, links)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_repost() {
+        let attachments: Vec<Attachment> = serde_json::from_str(
+            r#"[
+                {"type": "photo", "photo": {"id": 1, "owner_id": -2, "access_key": "k", "sizes": []}},
+                {"type": "doc", "doc": {"id": 3, "owner_id": 4, "title": "a.pdf"}},
+                {"type": "link", "link": {"url": "https://example.com", "title": "Example"}},
+                {"type": "poll", "poll": {"id": 5, "owner_id": -2, "question": "?"}},
+                {"type": "sticker", "sticker": {"sticker_id": 6}}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(Attachment::Unknown, attachments[4]);
+        assert_eq!(
+            (
+                Some("photo-2_1_k,doc4_3,poll-2_5".to_string()),
+                vec!["https://example.com".to_string()]
+            ),
+            repost(&attachments)
+        );
+
+        let photos = vec![attachments[0].clone(); MAX_ATTACHMENTS + 1];
+        let (attachment, links) = repost(&photos);
+        assert_eq!(MAX_ATTACHMENTS, attachment.unwrap().split(',').count());
+        assert_eq!(vec!["https://vk.com/photo-2_1".to_string()], links);
+        assert_eq!((None, vec![]), repost(&[]));
+    }
+}
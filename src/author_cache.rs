@@ -0,0 +1,111 @@
+use crate::client::Author;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Board post authors. Entries older than `ttl` seconds are
+/// resolved again, and the least recently used ones are dropped when
+/// there are more than `capacity` of them. Only the entries are stored
+/// in the state file, the limits are set with `limit` after load.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AuthorCache {
+    #[serde(skip)]
+    capacity: usize,
+    #[serde(skip)]
+    ttl: u64,
+    entries: HashMap<i64, Entry>,
+    #[serde(skip)]
+    tick: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    author: Author,
+    /// Unix time of the resolve, so the ttl survives restarts.
+    updated: u64,
+    #[serde(skip)]
+    used: u64,
+}
+
+impl AuthorCache {
+    pub fn limit(&mut self, capacity: usize, ttl: u64) {
+        self.capacity = capacity;
+        self.ttl = ttl;
+        self.evict();
+    }
+
+    pub fn get(&mut self, id: i64, now: u64) -> Option<Author> {
+        self.tick += 1;
+        let ttl = self.ttl;
+        match self.entries.get_mut(&id) {
+            Some(e) if e.updated + ttl > now => {
+                e.used = self.tick;
+                Some(e.author.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, author: Author, now: u64) {
+        self.tick += 1;
+        self.entries.insert(
+            author.id,
+            Entry {
+                author,
+                updated: now,
+                used: self.tick,
+            },
+        );
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| (e.used, e.updated))
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(id) => self.entries.remove(&id),
+                None => return,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn author(id: i64, name: &str) -> Author {
+        Author {
+            id,
+            name: name.to_owned(),
+            link: format!("https://vk.com/id{}", id),
+        }
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let mut cache = AuthorCache::default();
+        cache.limit(10, 100);
+        cache.insert(author(1, "Ivan"), 1000);
+        assert_eq!(Some(author(1, "Ivan")), cache.get(1, 1099));
+        assert_eq!(None, cache.get(1, 1100));
+        assert_eq!(None, cache.get(2, 1000));
+    }
+
+    #[test]
+    fn drop_least_recently_used() {
+        let mut cache = AuthorCache::default();
+        cache.limit(2, 100);
+        cache.insert(author(1, "a"), 0);
+        cache.insert(author(2, "b"), 0);
+        cache.get(1, 0);
+        cache.insert(author(-3, "c"), 0);
+        assert_eq!(Some(author(1, "a")), cache.get(1, 0));
+        assert_eq!(None, cache.get(2, 0));
+        assert_eq!(Some(author(-3, "c")), cache.get(-3, 0));
+    }
+}
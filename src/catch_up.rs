@@ -0,0 +1,169 @@
+use crate::client::{Client, Comment, WallPost};
+use crate::error::*;
+use crate::long_poll_client::{BoardComment, Event};
+use crate::state::State;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Latest board comments seen by the bot, to find the ones posted while
+/// it was down.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BoardCursor {
+    /// Last comment id by topic.
+    #[serde(default)]
+    topics: HashMap<i64, i64>,
+    /// Unix time of the latest comment.
+    #[serde(default)]
+    updated: i64,
+}
+
+impl BoardCursor {
+    pub fn update(&mut self, topic_id: i64, id: i64, date: i64) {
+        let last = self.topics.entry(topic_id).or_default();
+        *last = (*last).max(id);
+        self.updated = self.updated.max(date);
+    }
+
+    /// Comments after the last seen one, or after the latest seen date for
+    /// topics the bot doesn't know yet.
+    fn is_missed(&self, topic_id: i64, comment: &Comment) -> bool {
+        match self.topics.get(&topic_id) {
+            Some(last) => comment.id > *last,
+            None => comment.date > self.updated,
+        }
+    }
+}
+
+/// Posts missed on the board and on the wall, in order of their dates.
+/// Errors are only logged, as missed posts are not worth stopping the bot.
+pub async fn missed(client: &Client, state: &State) -> Vec<Event> {
+    let mut events = vec![];
+    match board(client, &state.board).await {
+        Ok(e) => events.extend(e),
+        Err(e) => error!("can't catch up board: {}", e),
+    }
+    match wall(client, state.last_wall_post).await {
+        Ok(e) => events.extend(e),
+        Err(e) => error!("can't catch up wall: {}", e),
+    }
+    events.sort_by_key(|e| match e {
+        Event::BoardPost(c) => (c.date, c.id),
+        Event::WallPost { date, id, .. } => (*date, *id),
+        _ => (0, 0),
+    });
+    if !events.is_empty() {
+        info!("found {} missed posts", events.len());
+    }
+    events
+}
+
+/// Fetches comments of the topics updated since the latest seen comment.
+/// Nothing is fetched before the bot has seen any comment.
+async fn board(client: &Client, cursor: &BoardCursor) -> SimpleResult<Vec<Event>> {
+    if cursor.updated == 0 {
+        return Ok(vec![]);
+    }
+    let topics = client.get_recent_topics().await?;
+    let mut events = vec![];
+    for topic in topics.iter().filter(|t| t.updated > cursor.updated) {
+        let comments = client.get_comments(topic.id).await?;
+        let all = comments.len();
+        let missed = missed_comments(cursor, topic.id, comments);
+        if all > 0 && missed.len() == all {
+            warn!("there may be more missed comments in topic {}", topic.id);
+        }
+        events.extend(missed);
+    }
+    Ok(events)
+}
+
+/// Fetches wall posts after the last forwarded one, if there is one.
+async fn wall(client: &Client, last: i64) -> SimpleResult<Vec<Event>> {
+    if last == 0 {
+        return Ok(vec![]);
+    }
+    let posts = client.get_wall_posts().await?;
+    Ok(missed_posts(last, posts))
+}
+
+fn missed_posts(last: i64, posts: Vec<WallPost>) -> Vec<Event> {
+    posts
+        .into_iter()
+        .filter(|p| p.id > last)
+        .map(|p| Event::WallPost {
+            id: p.id,
+            text: p.text,
+            date: p.date,
+        })
+        .collect()
+}
+
+fn missed_comments(cursor: &BoardCursor, topic_id: i64, comments: Vec<Comment>) -> Vec<Event> {
+    comments
+        .into_iter()
+        .filter(|c| cursor.is_missed(topic_id, c))
+        .map(|c| {
+            Event::BoardPost(BoardComment {
+                from_id: c.from_id,
+                text: c.text,
+                topic_id,
+                id: c.id,
+                date: c.date,
+                attachments: c.attachments,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn comment(id: i64, date: i64) -> Comment {
+        Comment {
+            id,
+            from_id: 1000,
+            date,
+            text: "text".to_owned(),
+            attachments: vec![],
+        }
+    }
+
+    fn ids(events: &[Event]) -> Vec<i64> {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::BoardPost(c) => c.id,
+                Event::WallPost { id, .. } => *id,
+                _ => 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_missed() {
+        let mut cursor = BoardCursor::default();
+        cursor.update(1, 10, 100);
+        cursor.update(1, 8, 90);
+        cursor.update(2, 5, 50);
+        assert_eq!(100, cursor.updated);
+
+        let comments = vec![comment(12, 120), comment(11, 110), comment(10, 100)];
+        assert_eq!(vec![12, 11], ids(&missed_comments(&cursor, 1, comments)));
+        let comments = vec![comment(3, 130), comment(2, 100), comment(1, 20)];
+        assert_eq!(vec![3], ids(&missed_comments(&cursor, 3, comments)));
+    }
+
+    #[test]
+    fn find_missed_wall_posts() {
+        let post = |id, date| WallPost {
+            id,
+            date,
+            text: String::new(),
+        };
+        // the pinned post comes first
+        let posts = vec![post(1, 10), post(7, 70), post(6, 60), post(5, 50)];
+        assert_eq!(vec![7, 6], ids(&missed_posts(5, posts)));
+    }
+}
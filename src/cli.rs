@@ -0,0 +1,151 @@
+pub const USAGE: &str = "\
+usage: vk-bot-repeat-rust [command] [options] [--config <file>] [--<section>.<key> <value>...]
+
+commands:
+  run                              start the bot, the default
+  check-config                     validate the config and the token
+  send --peer <id> --text <text>   send a test message
+  whoami                           show the group of the token
+  reset-state                      forget the long poll server in the state file
+  replay <file>                    repeat recorded long poll responses
+  help                             show this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Subcommand {
+    Run,
+    CheckConfig,
+    Send { peer_id: i64, text: String },
+    Whoami,
+    ResetState,
+    Replay { file: String },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub subcommand: Subcommand,
+    /// Options left for `config::load`.
+    pub config_args: Vec<String>,
+}
+
+/// Splits the arguments into the command with its own options and the
+/// config options. Without a command the bot runs.
+pub fn parse(args: &[String]) -> Result<Cli, String> {
+    let (name, rest) = match args.split_first() {
+        Some((name, rest)) if !name.starts_with("--") => (name.as_str(), rest),
+        _ => ("run", args),
+    };
+    let mut config_args = vec![];
+    let mut positional = vec![];
+    let mut peer_id = None;
+    let mut text = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--help" => return Ok(help()),
+            "--peer" if name == "send" => {
+                let value = rest.next().ok_or("no value for --peer")?;
+                peer_id = Some(value.parse().map_err(|_| "--peer should be a number")?);
+            }
+            "--text" if name == "send" => {
+                text = Some(rest.next().ok_or("no value for --text")?.clone());
+            }
+            flag if flag.starts_with("--") => {
+                config_args.push(arg.clone());
+                if !flag.contains('=') {
+                    config_args.extend(rest.next().cloned());
+                }
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    let subcommand = match (name, positional.as_slice()) {
+        ("run", []) => Subcommand::Run,
+        ("check-config", []) => Subcommand::CheckConfig,
+        ("send", []) => Subcommand::Send {
+            peer_id: peer_id.ok_or("send needs --peer")?,
+            text: text.ok_or("send needs --text")?,
+        },
+        ("whoami", []) => Subcommand::Whoami,
+        ("reset-state", []) => Subcommand::ResetState,
+        ("replay", [file]) => Subcommand::Replay { file: file.clone() },
+        ("replay", _) => return Err("replay needs one file".to_string()),
+        ("help", _) => return Ok(help()),
+        (_, [arg, ..]) if is_command(name) => return Err(format!("unexpected argument {}", arg)),
+        _ => return Err(format!("unknown command {}", name)),
+    };
+    Ok(Cli {
+        subcommand,
+        config_args,
+    })
+}
+
+fn is_command(name: &str) -> bool {
+    ["run", "check-config", "send", "whoami", "reset-state"].contains(&name)
+}
+
+fn help() -> Cli {
+    Cli {
+        subcommand: Subcommand::Help,
+        config_args: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_line(s: &str) -> Result<Cli, String> {
+        let args: Vec<String> = s.split_whitespace().map(|s| s.to_string()).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn subcommands() {
+        let cli = parse_line("--config bot.toml --vk.requests_per_second=5").unwrap();
+        assert_eq!(Subcommand::Run, cli.subcommand);
+        assert_eq!(
+            vec!["--config", "bot.toml", "--vk.requests_per_second=5"],
+            cli.config_args
+        );
+
+        let cli = parse_line("send --peer 2000000001 --config bot.toml --text hi").unwrap();
+        assert_eq!(
+            Subcommand::Send {
+                peer_id: 2000000001,
+                text: "hi".to_string()
+            },
+            cli.subcommand
+        );
+        assert_eq!(vec!["--config", "bot.toml"], cli.config_args);
+
+        let cli = parse_line("replay events.jsonl").unwrap();
+        assert_eq!(
+            Subcommand::Replay {
+                file: "events.jsonl".to_string()
+            },
+            cli.subcommand
+        );
+        assert_eq!(
+            Subcommand::Help,
+            parse_line("whoami --help").unwrap().subcommand
+        );
+
+        assert_eq!(
+            Err("send needs --text".to_string()),
+            parse_line("send --peer 1")
+        );
+        assert_eq!(
+            Err("replay needs one file".to_string()),
+            parse_line("replay")
+        );
+        assert_eq!(
+            Err("unknown command start".to_string()),
+            parse_line("start")
+        );
+        assert_eq!(
+            Err("unexpected argument x".to_string()),
+            parse_line("whoami x")
+        );
+    }
+}
@@ -1,26 +1,61 @@
+use crate::attachment::{self, Attachment};
 use crate::error::*;
+use crate::execute::{self, ApiCall, MAX_CALLS};
+use crate::metrics::metrics;
+use crate::rate_limiter::RateLimiter;
+use log::{info, warn};
 use rand::random;
 use reqwest::Response;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// How many times a request is repeated when VK answers with a rate limit error.
+const RATE_LIMIT_RETRIES: u64 = 5;
 
 pub struct Client {
     client: reqwest::Client,
     url: String,
     token: String,
+    /// Board and wall methods don't accept a group token.
+    service_token: Option<String>,
     group_id: u64,
+    limiter: RateLimiter,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ResponseWrapper<T> {
     response: Option<T>,
     error: Option<ErrorDescription>,
+    #[serde(default)]
+    execute_errors: Vec<ErrorDescription>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ErrorDescription {
     error_code: u64,
     error_msg: String,
+    #[serde(default)]
+    request_params: Vec<RequestParam>,
+    /// Only set for errors of calls inside `execute`.
+    method: Option<String>,
+}
+
+impl ErrorDescription {
+    fn into_api_error(self, method: &str) -> ApiError {
+        ApiError {
+            method: self.method.unwrap_or_else(|| method.to_string()),
+            error_code: self.error_code,
+            error_msg: self.error_msg,
+            request_params: self.request_params,
+        }
+    }
+
+    fn into_error(self, method: &str) -> Error {
+        Error::Api(self.into_api_error(method))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,21 +66,177 @@ pub struct ServerConfig {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct User {
-    pub first_name: Option<String>,
-    pub last_name: Option<String>,
+struct SendResult {
+    peer_id: i64,
+    conversation_message_id: Option<i64>,
+    error: Option<PeerError>,
+}
+
+/// Error of a single chat in `messages.send` with `peer_ids`, shaped unlike
+/// the errors of whole calls.
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerError {
+    code: u64,
+    description: String,
+}
+
+impl PeerError {
+    fn into_error(self) -> Error {
+        Error::Api(ApiError {
+            method: "messages.send".to_string(),
+            error_code: self.code,
+            error_msg: self.description,
+            request_params: vec![],
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct User {
+    id: i64,
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Group {
+    id: i64,
+    name: String,
+    screen_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Topic {
+    pub id: i64,
+    pub title: String,
+    /// Unix time of the last comment.
+    #[serde(default)]
+    pub updated: i64,
+}
+
+/// Board comment from `board.getComments`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Comment {
+    pub id: i64,
+    pub from_id: i64,
+    pub date: i64,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default, deserialize_with = "attachment::lenient")]
+    pub attachments: Vec<Attachment>,
+}
+
+/// Wall post from `wall.get`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WallPost {
+    pub id: i64,
+    pub date: i64,
+    #[serde(default)]
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmationCode {
+    code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Items<T> {
+    items: Vec<T>,
+}
+
+/// Author of a post: a user, or a community for a negative id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Author {
+    pub id: i64,
+    pub name: String,
+    pub link: String,
+}
+
+impl Author {
+    /// Used when the author can't be resolved.
+    pub fn unknown(id: i64) -> Author {
+        let name = if id < 0 {
+            format!("club{}", -id)
+        } else {
+            format!("id{}", id)
+        };
+        Author {
+            id,
+            link: format!("https://vk.com/{}", name),
+            name,
+        }
+    }
+}
+
+impl From<User> for Author {
+    fn from(user: User) -> Author {
+        let name = format!(
+            "{} {}",
+            user.first_name.unwrap_or_default(),
+            user.last_name.unwrap_or_default()
+        );
+        Author {
+            id: user.id,
+            name: name.trim().to_string(),
+            link: format!("https://vk.com/id{}", user.id),
+        }
+    }
+}
+
+impl From<Group> for Author {
+    fn from(group: Group) -> Author {
+        let link = match group.screen_name {
+            Some(screen_name) => format!("https://vk.com/{}", screen_name),
+            None => format!("https://vk.com/club{}", group.id),
+        };
+        Author {
+            id: -group.id,
+            name: group.name,
+            link,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub peer_id: i64,
+    pub text: Option<String>,
+    pub attachment: Option<String>,
+    /// VK sends a message only once for the same `random_id`, so a retry
+    /// after a lost answer doesn't repeat it. Set by `Outgoing::new`.
+    #[serde(default)]
+    pub random_id: i64,
 }
 
 impl Client {
-    pub fn new(token: String, group_id: u64) -> Client {
+    pub fn new(token: String, group_id: u64, requests_per_second: u32) -> Client {
         Client {
             client: reqwest::Client::new(),
             url: "https://api.vk.com/method/".to_string(),
             token,
+            service_token: None,
             group_id,
+            limiter: RateLimiter::new(requests_per_second),
         }
     }
 
+    /// Points the client to a fake API in tests.
+    #[cfg(test)]
+    pub fn with_url(mut self, url: String) -> Client {
+        self.url = url;
+        self
+    }
+
+    pub fn with_service_token(mut self, service_token: Option<String>) -> Client {
+        self.service_token = service_token;
+        self
+    }
+
+    fn read_token(&self) -> &str {
+        self.service_token.as_ref().unwrap_or(&self.token)
+    }
+
     pub fn raw_client(&self) -> reqwest::Client {
         self.client.clone()
     }
@@ -59,17 +250,41 @@ impl Client {
         send(self, "groups.getLongPollServer", &query).await
     }
 
+    /// The group of the token.
+    pub async fn whoami(&self) -> SimpleResult<Author> {
+        let query = [("v", "5.100"), ("access_token", &self.token)];
+        let groups: Vec<Group> = send(self, "groups.getById", &query).await?;
+        groups
+            .into_iter()
+            .next()
+            .map(Author::from)
+            .ok_or_else(|| Error::new("no group for the token"))
+    }
+
+    /// Code the Callback API server has to return to confirm its address.
+    pub async fn callback_confirmation_code(&self) -> SimpleResult<String> {
+        let query = [
+            ("v", "5.100"),
+            ("group_id", &self.group_id.to_string()),
+            ("access_token", &self.token),
+        ];
+        let r: ConfirmationCode = send(self, "groups.getCallbackConfirmationCode", &query).await?;
+        Ok(r.code)
+    }
+
+    /// Sends a message and returns its `conversation_message_id`,
+    /// which is needed to edit the message later.
     pub async fn send_message(
         &self,
         peer_id: i64,
         text: Option<String>,
         attachment: Option<String>,
-    ) -> SimpleResult<()> {
-        let peer_id = peer_id.to_string();
+    ) -> SimpleResult<i64> {
+        let peer_ids = peer_id.to_string();
         let rand = random::<i64>().abs().to_string();
         let mut query: Vec<(&str, &str)> = vec![
             ("v", "5.100"),
-            ("peer_id", &peer_id),
+            ("peer_ids", &peer_ids),
             ("random_id", &rand),
             ("access_token", &self.token),
         ];
@@ -82,27 +297,188 @@ impl Client {
             query.push(("attachment", attachment));
         }
 
-        let _: i64 = send(self, "messages.send", &query).await?;
+        let r: Vec<SendResult> = send(self, "messages.send", &query).await?;
+        conversation_message_id(r, peer_id)
+    }
+
+    /// Sends messages in as few `execute` requests as possible, in order.
+    /// Returns a result for every message, like `send_message`.
+    pub async fn send_messages(&self, messages: &[Message]) -> Vec<SimpleResult<i64>> {
+        let calls: Vec<ApiCall> = messages.iter().map(message_call).collect();
+        self.execute(&calls)
+            .await
+            .into_iter()
+            .zip(messages)
+            .map(|(r, m)| {
+                let r: Vec<SendResult> = serde_json::from_value(r?)
+                    .map_err(|e| e.wrap("can't parse messages.send result"))?;
+                conversation_message_id(r, m.peer_id)
+            })
+            .collect()
+    }
+
+    /// Runs the calls through the `execute` method, up to `MAX_CALLS` per request.
+    pub async fn execute(&self, calls: &[ApiCall]) -> Vec<SimpleResult<Value>> {
+        let mut result = Vec::with_capacity(calls.len());
+        for chunk in calls.chunks(MAX_CALLS) {
+            let code = execute::script(chunk);
+            let query = [
+                ("v", "5.100"),
+                ("code", &code),
+                ("access_token", &self.token),
+            ];
+            let r: SimpleResult<(Vec<Value>, _)> = send_with_errors(self, "execute", &query).await;
+            match r {
+                Ok((values, errors)) => {
+                    let errors = errors
+                        .into_iter()
+                        .map(|e| e.into_api_error("execute"))
+                        .collect();
+                    result.extend(execute::unpack(chunk, values, errors))
+                }
+                Err(e) => result.extend(chunk.iter().map(|_| Err(e.clone()))),
+            }
+        }
+        result
+    }
+
+    pub async fn edit_message(
+        &self,
+        peer_id: i64,
+        conversation_message_id: i64,
+        text: String,
+        attachment: Option<String>,
+    ) -> SimpleResult<()> {
+        let peer_id = peer_id.to_string();
+        let conversation_message_id = conversation_message_id.to_string();
+        let mut query: Vec<(&str, &str)> = vec![
+            ("v", "5.100"),
+            ("peer_id", &peer_id),
+            ("conversation_message_id", &conversation_message_id),
+            ("message", &text),
+            ("access_token", &self.token),
+        ];
+        if let Some(attachment) = &attachment {
+            query.push(("attachment", attachment));
+        }
+        let _: i64 = send(self, "messages.edit", &query).await?;
         Ok(())
     }
 
-    pub async fn get_user(&self, user_id: i64) -> SimpleResult<User> {
-        let user_id = user_id.to_string();
+    pub async fn get_topics(&self, topic_ids: &[i64]) -> SimpleResult<Vec<Topic>> {
+        let topic_ids: Vec<String> = topic_ids.iter().map(|id| id.to_string()).collect();
+        let topic_ids = topic_ids.join(",");
         let query = [
             ("v", "5.100"),
-            ("user_ids", &user_id),
-            ("access_token", &self.token),
+            ("group_id", &self.group_id.to_string()),
+            ("topic_ids", &topic_ids),
+            ("access_token", self.read_token()),
         ];
+        let r: Items<Topic> = send(self, "board.getTopics", &query).await?;
+        Ok(r.items)
+    }
 
-        let r: SimpleResult<Vec<User>> = send(self, "users.get", &query).await;
-        match r {
-            Err(e) => Err(e),
-            Ok(mut list) => match list.len() {
-                0 => Err(Error::new(format!("user {} not found", user_id))),
-                1 => Ok(list.pop().unwrap()),
-                _ => Err(Error::new(format!("many users: {} found", list.len()))),
-            },
+    /// Up to 100 topics, the last updated first.
+    pub async fn get_recent_topics(&self) -> SimpleResult<Vec<Topic>> {
+        let query = [
+            ("v", "5.100"),
+            ("group_id", &self.group_id.to_string()),
+            ("order", "1"),
+            ("count", "100"),
+            ("access_token", self.read_token()),
+        ];
+        let r: Items<Topic> = send(self, "board.getTopics", &query).await?;
+        Ok(r.items)
+    }
+
+    /// Up to 100 posts of the group on its wall, the newest first
+    /// except for the pinned one.
+    pub async fn get_wall_posts(&self) -> SimpleResult<Vec<WallPost>> {
+        let owner_id = format!("-{}", self.group_id);
+        let query = [
+            ("v", "5.100"),
+            ("owner_id", &owner_id),
+            ("filter", "owner"),
+            ("count", "100"),
+            ("access_token", self.read_token()),
+        ];
+        let r: Items<WallPost> = send(self, "wall.get", &query).await?;
+        Ok(r.items)
+    }
+
+    /// Up to 100 comments of the topic, the newest first.
+    pub async fn get_comments(&self, topic_id: i64) -> SimpleResult<Vec<Comment>> {
+        let query = [
+            ("v", "5.100"),
+            ("group_id", &self.group_id.to_string()),
+            ("topic_id", &topic_id.to_string()),
+            ("sort", "desc"),
+            ("count", "100"),
+            ("access_token", self.read_token()),
+        ];
+        let r: Items<Comment> = send(self, "board.getComments", &query).await?;
+        Ok(r.items)
+    }
+
+    /// Resolves authors with `users.get` for positive ids and
+    /// `groups.getById` for negative ones.
+    pub async fn get_authors(&self, ids: &[i64]) -> SimpleResult<Vec<Author>> {
+        let user_ids: Vec<String> = ids
+            .iter()
+            .filter(|id| **id > 0)
+            .map(|id| id.to_string())
+            .collect();
+        let group_ids: Vec<String> = ids
+            .iter()
+            .filter(|id| **id < 0)
+            .map(|id| (-id).to_string())
+            .collect();
+        let mut authors = vec![];
+        if !user_ids.is_empty() {
+            let user_ids = user_ids.join(",");
+            let query = [
+                ("v", "5.100"),
+                ("user_ids", &user_ids),
+                ("access_token", &self.token),
+            ];
+            let users: Vec<User> = send(self, "users.get", &query).await?;
+            authors.extend(users.into_iter().map(Author::from));
+        }
+        if !group_ids.is_empty() {
+            let group_ids = group_ids.join(",");
+            let query = [
+                ("v", "5.100"),
+                ("group_ids", &group_ids),
+                ("access_token", &self.token),
+            ];
+            let groups: Vec<Group> = send(self, "groups.getById", &query).await?;
+            authors.extend(groups.into_iter().map(Author::from));
         }
+        Ok(authors)
+    }
+}
+
+fn message_call(message: &Message) -> ApiCall {
+    let mut call = ApiCall::new("messages.send")
+        .param("peer_ids", message.peer_id)
+        .param("random_id", message.random_id);
+    if let Some(text) = &message.text {
+        call = call.param("message", text);
+    }
+    if let Some(attachment) = &message.attachment {
+        call = call.param("attachment", attachment);
+    }
+    call
+}
+
+fn conversation_message_id(mut r: Vec<SendResult>, peer_id: i64) -> SimpleResult<i64> {
+    match r.pop() {
+        Some(SendResult {
+            conversation_message_id: Some(id),
+            ..
+        }) => Ok(id),
+        Some(SendResult { error: Some(e), .. }) => Err(e.into_error()),
+        _ => Err(Error::new(format!("no message sent to {}", peer_id))),
     }
 }
 
@@ -111,6 +487,48 @@ async fn send<T: DeserializeOwned, TQuery: Serialize + ?Sized>(
     method: &str,
     query: &TQuery,
 ) -> SimpleResult<T> {
+    send_with_errors(client, method, query)
+        .await
+        .map(|(r, _)| r)
+}
+
+/// Like `send`, but also returns `execute_errors` of the response.
+async fn send_with_errors<T: DeserializeOwned, TQuery: Serialize + ?Sized>(
+    client: &Client,
+    method: &str,
+    query: &TQuery,
+) -> SimpleResult<(T, Vec<ErrorDescription>)> {
+    let mut attempt = 0;
+    loop {
+        client.limiter.acquire().await;
+        let started = Instant::now();
+        let r = send_once(client, method, query).await;
+        let latency = started.elapsed();
+        metrics()
+            .api_latency
+            .with_label_values(&[method])
+            .observe(latency.as_secs_f64());
+        info!(
+            method,
+            latency_ms = latency.as_millis() as u64;
+            "{} took {:?}", method, latency
+        );
+        match r {
+            Err(e) if e.class() == ErrorClass::RateLimit && attempt < RATE_LIMIT_RETRIES => {
+                attempt += 1;
+                warn!("{}, retry {} in {} s", e, attempt, attempt);
+                sleep(Duration::from_secs(attempt)).await;
+            }
+            r => return r,
+        }
+    }
+}
+
+async fn send_once<T: DeserializeOwned, TQuery: Serialize + ?Sized>(
+    client: &Client,
+    method: &str,
+    query: &TQuery,
+) -> SimpleResult<(T, Vec<ErrorDescription>)> {
     let url: String = client.url.clone() + method;
     let r: Response = client
         .client
@@ -122,10 +540,10 @@ async fn send<T: DeserializeOwned, TQuery: Serialize + ?Sized>(
     let status = r.status();
     let text = r.text().await.map_err(|e| wrap(&e, method))?;
     if !status.is_success() {
-        return Err(Error::new(format!(
-            "got status {} on send {} with body {}",
-            status, method, &text
-        )));
+        return Err(Error::Transport {
+            message: format!("got status on send {} with body {}", method, &text),
+            status: Some(status.as_u16()),
+        });
     }
     let r: ResponseWrapper<T> = serde_json::from_str(&text).map_err(|e| {
         Error::new(format!(
@@ -133,12 +551,20 @@ async fn send<T: DeserializeOwned, TQuery: Serialize + ?Sized>(
             e, &text, method
         ))
     })?;
-    if r.error.is_some() || r.response.is_none() {
-        return Err(Error::new(format!("got <{}> from {}", &text, method)));
+    match r {
+        ResponseWrapper { error: Some(e), .. } => Err(e.into_error(method)),
+        ResponseWrapper {
+            response: Some(response),
+            execute_errors,
+            ..
+        } => Ok((response, execute_errors)),
+        _ => Err(Error::new(format!("got <{}> from {}", &text, method))),
     }
-    Ok(r.response.unwrap())
 }
 
 fn wrap(e: &reqwest::Error, method: &str) -> Error {
-    Error::new(format!("got {:?} from {}", e, method))
+    Error::Transport {
+        message: format!("got {:?} from {}", e, method),
+        status: e.status().map(|s| s.as_u16()),
+    }
 }
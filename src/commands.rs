@@ -0,0 +1,56 @@
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Status,
+    Mute(i64),
+    Unmute(i64),
+}
+
+pub const HELP: &str = "/status - bot status\n\
+/mute <topic> - stop repeating the topic to this chat\n\
+/unmute <topic> - repeat the topic again\n\
+/help - this message";
+
+/// Parses a chat message. Returns `None` for anything that is not a command,
+/// and an error text for a command with bad arguments.
+pub fn parse(text: &str) -> Option<Result<Command, String>> {
+    let mut parts = text.split_whitespace();
+    let name = parts.next()?.strip_prefix('/')?;
+    let command = match name {
+        "help" => Ok(Command::Help),
+        "status" => Ok(Command::Status),
+        "mute" => topic(parts.next()).map(Command::Mute),
+        "unmute" => topic(parts.next()).map(Command::Unmute),
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn topic(arg: Option<&str>) -> Result<i64, String> {
+    let arg = arg.ok_or_else(|| "topic id expected".to_string())?;
+    let id = arg.rsplit('_').next().unwrap_or(arg);
+    id.parse().map_err(|_| format!("bad topic id {}", arg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Some(Ok(Command::Help)), parse("/help"));
+        assert_eq!(Some(Ok(Command::Status)), parse(" /status now"));
+        assert_eq!(Some(Ok(Command::Mute(123))), parse("/mute 123"));
+        assert_eq!(Some(Ok(Command::Mute(123))), parse("/mute topic-1_123"));
+        assert_eq!(Some(Ok(Command::Unmute(5))), parse("/unmute 5"));
+    }
+
+    #[test]
+    fn parse_not_commands() {
+        assert_eq!(None, parse(""));
+        assert_eq!(None, parse("hello"));
+        assert_eq!(None, parse("/unknown"));
+        assert!(parse("/mute").unwrap().is_err());
+        assert!(parse("/mute abc").unwrap().is_err());
+    }
+}
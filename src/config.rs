@@ -1,31 +1,811 @@
+use crate::health::{DEFAULT_STALL_TIMEOUT, MIN_STALL_TIMEOUT};
+use crate::retry::{CircuitBreaker, Retry, RetryPolicy};
+use crate::routing::RoutingSection;
+use crate::template::{Field, TemplatesSection};
+use crate::worker::Settings;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Every setting as `<section>.<key>` in the config file and on the command
+/// line, with its environment variable.
+const SETTINGS: &[(&str, &str)] = &[
+    ("vk.token", "VK_BOT_TOKEN"),
+    ("vk.service_token", "VK_BOT_SERVICE_TOKEN"),
+    ("vk.group", "VK_BOT_GROUP"),
+    ("vk.requests_per_second", "VK_BOT_RPS"),
+    ("vk.author_cache_size", "VK_BOT_AUTHOR_CACHE_SIZE"),
+    ("vk.author_cache_ttl", "VK_BOT_AUTHOR_CACHE_TTL"),
+    ("routing.chat", "VK_BOT_CHAT"),
+    ("routing.file", "VK_BOT_ROUTES"),
+    ("templates.file", "VK_BOT_TEMPLATES"),
+    ("retry.poll", "VK_BOT_RETRY_POLL"),
+    ("retry.send", "VK_BOT_RETRY_SEND"),
+    ("retry.lookup", "VK_BOT_RETRY_LOOKUP"),
+    ("retry.breaker_failures", "VK_BOT_BREAKER_FAILURES"),
+    ("retry.breaker_cool_down", "VK_BOT_BREAKER_COOL_DOWN"),
+    ("retry.outbox_attempts", "VK_BOT_OUTBOX_ATTEMPTS"),
+    ("storage.state_file", "VK_BOT_FILE"),
+    ("storage.dead_letter", "VK_BOT_DEAD_LETTER"),
+    ("source.mode", "VK_BOT_MODE"),
+    ("source.replay_file", "VK_BOT_REPLAY"),
+    ("http.addr", "VK_BOT_HTTP_ADDR"),
+    ("http.callback_path", "VK_BOT_CALLBACK_PATH"),
+    ("http.callback_secret", "VK_BOT_CALLBACK_SECRET"),
+    ("http.callback_confirmation", "VK_BOT_CALLBACK_CONFIRMATION"),
+    ("http.stall_timeout", "VK_BOT_STALL_TIMEOUT"),
+    ("log.format", "VK_BOT_LOG_FORMAT"),
+];
+
+const MODES: &[&str] = &["long_poll", "callback", "replay"];
+const LOG_FORMATS: &[&str] = &["text", "json"];
+
+/// Settings from the environment and the command line by `<section>.<key>`,
+/// as strings before parsing.
+type Values = BTreeMap<String, String>;
+
+/// Settings which need a restart. Routes and templates are `Settings`, as
+/// they are reloaded on SIGHUP.
+#[derive(Debug)]
+pub struct Config {
+    pub vk: VkConfig,
+    pub retry: RetryConfig,
+    pub storage: StorageConfig,
+    pub source: SourceConfig,
+    pub http: HttpConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug)]
+pub struct VkConfig {
+    pub token: String,
+    /// Service token of the VK app, for methods that don't accept a group token.
+    pub service_token: Option<String>,
+    pub group_id: u64,
+    /// VK allows about 20 requests per second for a group token.
+    pub requests_per_second: u32,
+    pub author_cache_size: usize,
+    /// Seconds after which a cached author is resolved again.
+    pub author_cache_ttl: u64,
+}
+
+/// Retry policies for waiting for events, sending messages and resolving
+/// names, and the circuit breaker for the VK API.
+#[derive(Debug)]
+pub struct RetryConfig {
+    pub poll: RetryPolicy,
+    pub send: RetryPolicy,
+    pub lookup: RetryPolicy,
+    pub breaker_failures: u32,
+    pub breaker_cool_down: Duration,
+    /// Sends of a message before it is given up.
+    pub outbox_attempts: u32,
+}
+
+#[derive(Debug)]
+pub struct StorageConfig {
+    /// Long poll server, caches and unsent messages.
+    pub state_file: Option<String>,
+    /// Messages given up are appended to this file.
+    pub dead_letter: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct SourceConfig {
+    /// `long_poll` (default) or `callback` to receive events from VK, or
+    /// `replay` to repeat events recorded in `replay_file`.
+    pub mode: String,
+    /// Long poll responses, one per line.
+    pub replay_file: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct HttpConfig {
+    /// Address of the server with `/metrics` and the Callback API. Without
+    /// it the server only starts in callback mode, on `0.0.0.0:8080`.
+    pub addr: Option<SocketAddr>,
+    pub callback_path: String,
+    pub callback_secret: Option<String>,
+    /// Requested from VK when not set.
+    pub callback_confirmation: Option<String>,
+    /// Seconds without progress of the worker after which `/healthz` fails.
+    pub stall_timeout: u64,
+}
+
+#[derive(Debug)]
+pub struct LogConfig {
+    /// `text`, or `json` for a JSON object per line.
+    pub format: String,
+}
+
+/// The config file. Every setting is optional here, as it may come from the
+/// environment or the command line instead.
+#[derive(Debug, Default)]
+struct File {
+    vk: VkSection,
+    routing: RoutingSection,
+    templates: TemplatesSection,
+    retry: RetrySection,
+    storage: StorageSection,
+    source: SourceSection,
+    http: HttpSection,
+    log: LogSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VkSection {
+    token: Option<String>,
+    service_token: Option<String>,
+    group: Option<u64>,
+    requests_per_second: Option<u32>,
+    author_cache_size: Option<usize>,
+    author_cache_ttl: Option<u64>,
+}
+
+/// Delays are `[<initial ms>, <max ms>]`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetrySection {
+    poll: Option<(u64, u64)>,
+    send: Option<(u64, u64)>,
+    lookup: Option<(u64, u64)>,
+    breaker_failures: Option<u32>,
+    breaker_cool_down: Option<u64>,
+    outbox_attempts: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StorageSection {
+    state_file: Option<String>,
+    dead_letter: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceSection {
+    mode: Option<String>,
+    replay_file: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HttpSection {
+    addr: Option<SocketAddr>,
+    callback_path: Option<String>,
+    callback_secret: Option<String>,
+    callback_confirmation: Option<String>,
+    stall_timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogSection {
+    format: Option<String>,
+}
+
+impl RetryConfig {
+    pub fn retry(&self) -> Retry {
+        Retry::new(
+            self.poll,
+            self.send,
+            self.lookup,
+            CircuitBreaker::new(self.breaker_failures, self.breaker_cool_down),
+        )
+    }
+}
+
+/// All problems found in the config.
+#[derive(Debug)]
+pub struct Errors(pub Vec<String>);
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad config:")?;
+        for e in &self.0 {
+            write!(f, "\n  {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// The config loaded at start.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config is not loaded")
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn init(config: Config) {
+    CONFIG.set(config).expect("config is already loaded");
+}
+
+/// Reads the config file from `--config` or `VK_BOT_CONFIG`, overridden by
+/// the environment, overridden by `--<section>.<key> <value>` arguments.
+/// Routes and templates are loaded from their files too, so that every
+/// problem is reported at once.
+pub fn load(args: &[String]) -> Result<(Config, Settings), Errors> {
+    let mut errors = vec![];
+    let (file_name, cli) = parse_args(args, &mut errors);
+    let file = match file_name.or_else(|| get_opt("VK_BOT_CONFIG")) {
+        Some(file_name) => match std::fs::read_to_string(&file_name) {
+            Ok(text) => from_toml(&text, &mut errors),
+            Err(e) => {
+                errors.push(format!("can't read {}: {}", file_name, e));
+                File::default()
+            }
+        },
+        None => File::default(),
+    };
+    let mut values = from_env(get_opt);
+    values.extend(cli);
+    resolve(file, &values, errors)
+}
+
+/// Applies `values` over the file and checks the result.
+fn resolve(
+    file: File,
+    values: &Values,
+    mut errors: Vec<String>,
+) -> Result<(Config, Settings), Errors> {
+    let mut p = Parser {
+        values,
+        errors: &mut errors,
+    };
+    let config = Config::parse(&file, &mut p);
+    let settings = settings(file.routing, file.templates, &config, &mut p);
+    match settings {
+        Some(settings) if errors.is_empty() => Ok((config, settings)),
+        _ => Err(Errors(errors)),
+    }
+}
+
+/// Routes and templates, which are also reloaded on SIGHUP.
+fn settings(
+    mut routing: RoutingSection,
+    mut templates: TemplatesSection,
+    config: &Config,
+    p: &mut Parser,
+) -> Option<Settings> {
+    routing.chat = p.opt("routing.chat", routing.chat);
+    routing.file = p.opt("routing.file", routing.file.take());
+    templates.file = p.opt("templates.file", templates.file.take());
+    let routes = match routing.load() {
+        Some(routes) => routes.map_err(|e| p.errors.push(e.to_string())).ok(),
+        // a chat that can't be parsed is reported already
+        None if p.values.contains_key("routing.chat") => None,
+        None => {
+            p.missing("routing.chat");
+            None
+        }
+    };
+    let templates = templates
+        .load()
+        .map_err(|e| p.errors.push(e.to_string()))
+        .ok();
+    // board.getTopics fails with a group token
+    let topic_title = templates
+        .as_ref()
+        .is_some_and(|t| t.uses(Field::TopicTitle));
+    if topic_title && config.vk.service_token.is_none() {
+        p.errors.push(format!(
+            "{{topic_title}} in templates needs vk.service_token ({})",
+            env_name("vk.service_token")
+        ));
+    }
+    Some(Settings {
+        routes: routes?,
+        templates: templates?,
+    })
+}
 
 fn get_opt(name: &str) -> Option<String> {
     env::var_os(name).and_then(|s| s.to_str().map(|s| s.to_string()))
 }
 
-fn get(name: &str) -> String {
-    match env::var_os(name) {
-        Some(val) => match val.to_str() {
-            Some(val) => val.into(),
-            None => panic!("Bad value in enviroment variable {} not present", name),
-        },
-        None => panic!("Enviroment variable {} not present", name),
+fn env_name(key: &str) -> &'static str {
+    SETTINGS
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, name)| *name)
+        .unwrap_or("")
+}
+
+fn is_known(key: &str) -> bool {
+    SETTINGS.iter().any(|(k, _)| *k == key)
+}
+
+fn from_env(get: impl Fn(&str) -> Option<String>) -> Values {
+    SETTINGS
+        .iter()
+        .filter_map(|(key, name)| get(name).map(|v| (key.to_string(), v)))
+        .collect()
+}
+
+/// Returns the config file and the settings from `--<key> <value>` or
+/// `--<key>=<value>`.
+fn parse_args(args: &[String], errors: &mut Vec<String>) -> (Option<String>, Values) {
+    let mut file = None;
+    let mut values = Values::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => {
+                errors.push(format!("unexpected argument {}", arg));
+                continue;
+            }
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_string())),
+            None => (flag, args.next().cloned()),
+        };
+        let value = match value {
+            Some(value) => value,
+            None => {
+                errors.push(format!("no value for --{}", key));
+                continue;
+            }
+        };
+        if key == "config" {
+            file = Some(value);
+        } else if is_known(key) {
+            values.insert(key.to_string(), value);
+        } else {
+            errors.push(format!("unknown option --{}", key));
+        }
+    }
+    (file, values)
+}
+
+/// Reads the sections of the config file. Each one is checked on its own,
+/// so that problems in several sections are reported together.
+fn from_toml(text: &str, errors: &mut Vec<String>) -> File {
+    let mut file = File::default();
+    let sections: BTreeMap<String, toml::Value> = match toml::from_str(text) {
+        Ok(v) => v,
+        Err(e) => {
+            errors.push(format!("can't parse config file: {}", e));
+            return file;
+        }
+    };
+    for (name, value) in sections {
+        match name.as_str() {
+            "vk" => file.vk = section(&name, value, errors),
+            "routing" => file.routing = section(&name, value, errors),
+            "templates" => file.templates = section(&name, value, errors),
+            "retry" => file.retry = section(&name, value, errors),
+            "storage" => file.storage = section(&name, value, errors),
+            "source" => file.source = section(&name, value, errors),
+            "http" => file.http = section(&name, value, errors),
+            "log" => file.log = section(&name, value, errors),
+            _ => errors.push(format!("unknown section [{}]", name)),
+        }
     }
+    file
+}
+
+fn section<T: DeserializeOwned + Default>(
+    name: &str,
+    value: toml::Value,
+    errors: &mut Vec<String>,
+) -> T {
+    value.try_into().unwrap_or_else(|e| {
+        errors.push(format!("[{}] {}", name, e));
+        T::default()
+    })
 }
 
-pub fn token() -> String {
-    get("VK_BOT_TOKEN")
+/// Takes every setting from the environment or the command line, or else
+/// from the file, and collects errors instead of stopping at the first one.
+struct Parser<'a> {
+    values: &'a Values,
+    errors: &'a mut Vec<String>,
+}
+
+impl Parser<'_> {
+    fn with<T>(
+        &mut self,
+        key: &str,
+        file: Option<T>,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<T> {
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => return file,
+        };
+        match parse(value) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                self.invalid(key, value, e);
+                None
+            }
+        }
+    }
+
+    /// Keeps the value if `check` passes.
+    fn check<T: fmt::Debug>(
+        &mut self,
+        key: &str,
+        value: Option<T>,
+        check: impl Fn(&T) -> Result<(), String>,
+    ) -> Option<T> {
+        let value = value?;
+        match check(&value) {
+            Ok(()) => Some(value),
+            Err(e) => {
+                self.invalid(key, value, e);
+                None
+            }
+        }
+    }
+
+    fn invalid(&mut self, key: &str, value: impl fmt::Debug, e: String) {
+        self.errors
+            .push(format!("{} ({}) = {:?}: {}", key, env_name(key), value, e));
+    }
+
+    fn opt<T: FromStr>(&mut self, key: &str, file: Option<T>) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        self.with(key, file, |s| {
+            s.trim().parse().map_err(|e: T::Err| e.to_string())
+        })
+    }
+
+    fn or<T: FromStr>(&mut self, key: &str, file: Option<T>, default: T) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        self.opt(key, file).unwrap_or(default)
+    }
+
+    fn at_least<T: FromStr + PartialOrd + fmt::Debug + fmt::Display>(
+        &mut self,
+        key: &str,
+        file: Option<T>,
+        default: T,
+        min: T,
+    ) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        let value = self.opt(key, file);
+        self.check(key, value, |v| {
+            if *v < min {
+                Err(format!("should be at least {}", min))
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap_or(default)
+    }
+
+    fn required<T: FromStr + Default>(&mut self, key: &str, file: Option<T>) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        if file.is_none() && !self.values.contains_key(key) {
+            self.missing(key);
+        }
+        self.opt(key, file).unwrap_or_default()
+    }
+
+    fn missing(&mut self, key: &str) {
+        self.errors
+            .push(format!("{} ({}) is not set", key, env_name(key)));
+    }
+
+    fn one_of(
+        &mut self,
+        key: &str,
+        file: Option<String>,
+        allowed: &[&str],
+        default: &str,
+    ) -> String {
+        let value = self.opt(key, file);
+        self.check(key, value, |s| {
+            if allowed.contains(&s.as_str()) {
+                Ok(())
+            } else {
+                Err(format!("should be one of {}", allowed.join(", ")))
+            }
+        })
+        .unwrap_or_else(|| default.to_string())
+    }
+
+    fn retry_policy(
+        &mut self,
+        key: &str,
+        file: Option<(u64, u64)>,
+        initial: u64,
+        max: u64,
+    ) -> RetryPolicy {
+        let delays = self.with(key, file, parse_delays);
+        let (initial, max) = self
+            .check(key, delays, |(initial, max)| {
+                if initial <= max {
+                    Ok(())
+                } else {
+                    Err("the first delay is longer than the max".to_string())
+                }
+            })
+            .unwrap_or((initial, max));
+        RetryPolicy {
+            initial: Duration::from_millis(initial),
+            max: Duration::from_millis(max),
+        }
+    }
 }
 
-pub fn group_id() -> u64 {
-    get("VK_BOT_GROUP").parse().expect("not int GROUP")
+/// First and max delay in milliseconds, e.g. `500,60000`.
+fn parse_delays(s: &str) -> Result<(u64, u64), String> {
+    let delays: Result<Vec<u64>, _> = s.split(',').map(|d| d.trim().parse()).collect();
+    match delays.as_deref() {
+        Ok([initial, max]) => Ok((*initial, *max)),
+        _ => Err("should be <initial ms>,<max ms>".to_string()),
+    }
 }
 
-pub fn chat_peer_id() -> i64 {
-    get("VK_BOT_CHAT").parse().expect("not int CHAT")
+impl Config {
+    fn parse(file: &File, p: &mut Parser) -> Config {
+        let f = &file.vk;
+        let vk = VkConfig {
+            token: p.required("vk.token", f.token.clone()),
+            service_token: p.opt("vk.service_token", f.service_token.clone()),
+            group_id: p.required("vk.group", f.group),
+            requests_per_second: p.at_least("vk.requests_per_second", f.requests_per_second, 20, 1),
+            author_cache_size: p.or("vk.author_cache_size", f.author_cache_size, 1000),
+            author_cache_ttl: p.or("vk.author_cache_ttl", f.author_cache_ttl, 24 * 60 * 60),
+        };
+        let f = &file.retry;
+        let retry = RetryConfig {
+            poll: p.retry_policy("retry.poll", f.poll, 1000, 5 * 60 * 1000),
+            send: p.retry_policy("retry.send", f.send, 1000, 60 * 1000),
+            lookup: p.retry_policy("retry.lookup", f.lookup, 1000, 60 * 1000),
+            breaker_failures: p.at_least("retry.breaker_failures", f.breaker_failures, 5, 1),
+            breaker_cool_down: Duration::from_secs(p.or(
+                "retry.breaker_cool_down",
+                f.breaker_cool_down,
+                60,
+            )),
+            outbox_attempts: p.at_least("retry.outbox_attempts", f.outbox_attempts, 10, 1),
+        };
+        let f = &file.storage;
+        let storage = StorageConfig {
+            state_file: p.opt("storage.state_file", f.state_file.clone()),
+            dead_letter: p.opt("storage.dead_letter", f.dead_letter.clone()),
+        };
+        let f = &file.source;
+        let source = SourceConfig {
+            mode: p.one_of("source.mode", f.mode.clone(), MODES, "long_poll"),
+            replay_file: p.opt("source.replay_file", f.replay_file.clone()),
+        };
+        if source.mode == "replay" && source.replay_file.is_none() {
+            p.missing("source.replay_file");
+        }
+        let f = &file.http;
+        let http = HttpConfig {
+            addr: p.opt("http.addr", f.addr),
+            callback_path: p.or(
+                "http.callback_path",
+                f.callback_path.clone(),
+                "/callback".to_string(),
+            ),
+            callback_secret: p.opt("http.callback_secret", f.callback_secret.clone()),
+            callback_confirmation: p.opt(
+                "http.callback_confirmation",
+                f.callback_confirmation.clone(),
+            ),
+            stall_timeout: p.at_least(
+                "http.stall_timeout",
+                f.stall_timeout,
+                DEFAULT_STALL_TIMEOUT,
+                MIN_STALL_TIMEOUT,
+            ),
+        };
+        // anyone who can reach the server could post events otherwise
+        if source.mode == "callback" && http.callback_secret.is_none() {
+            p.missing("http.callback_secret");
+        }
+        let log = LogConfig {
+            format: p.one_of("log.format", file.log.format.clone(), LOG_FORMATS, "text"),
+        };
+        Config {
+            vk,
+            retry,
+            storage,
+            source,
+            http,
+            log,
+        }
+    }
 }
 
-pub fn server_options_file() -> Option<String> {
-    get_opt("VK_BOT_FILE")
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::long_poll_client::{BoardComment, Event, EventKind};
+    use crate::routing::RoutingTable;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(|s| s.to_string()).collect()
+    }
+
+    /// Loads the config like `load`, with the environment from `env`.
+    fn load_with(
+        toml: &str,
+        env: impl Fn(&str) -> Option<String>,
+        line: &str,
+    ) -> Result<(Config, Settings), Errors> {
+        let mut errors = vec![];
+        let file = from_toml(toml, &mut errors);
+        let (_, cli) = parse_args(&args(line), &mut errors);
+        let mut values = from_env(env);
+        values.extend(cli);
+        resolve(file, &values, errors)
+    }
+
+    fn board(topic_id: i64, text: &str) -> Event {
+        Event::BoardPost(BoardComment {
+            from_id: 1,
+            text: text.to_owned(),
+            topic_id,
+            id: 1,
+            date: 0,
+            attachments: vec![],
+        })
+    }
+
+    #[test]
+    fn layers() {
+        let (config, settings) = load_with(
+            r#"
+            [vk]
+            token = "file token"
+            group = 1
+            [routing]
+            chat = 2000000001
+            [retry]
+            send = [500, 30000]
+            "#,
+            |name| match name {
+                "VK_BOT_GROUP" => Some("2".to_string()),
+                "VK_BOT_CHAT" => Some("2000000002".to_string()),
+                _ => None,
+            },
+            "--config bot.toml --routing.chat=3",
+        )
+        .unwrap();
+        assert_eq!("file token", config.vk.token);
+        assert_eq!(2, config.vk.group_id);
+        assert_eq!(RoutingTable::single(3), settings.routes);
+        assert_eq!(Duration::from_millis(500), config.retry.send.initial);
+        assert_eq!(Duration::from_secs(60), config.retry.lookup.max);
+        assert_eq!("long_poll", config.source.mode);
+    }
+
+    #[test]
+    fn all_errors() {
+        let errors = load_with(
+            r#"
+            [vk]
+            group = "abc"
+            [retry]
+            send = [60000, 500]
+            [source]
+            mode = "replay"
+            color = 1
+            [colors]
+            "#,
+            |_| None,
+            "--log.format xml --nope 1 --retry.outbox_attempts 0 --http.stall_timeout 30",
+        )
+        .unwrap_err();
+        assert_eq!(
+            vec![
+                "unknown section [colors]",
+                "[source] unknown field `color`, expected `mode` or `replay_file`",
+                "[vk] invalid type: string \"abc\", expected u64 for key `group`",
+                "unknown option --nope",
+                "vk.token (VK_BOT_TOKEN) is not set",
+                "vk.group (VK_BOT_GROUP) is not set",
+                "retry.send (VK_BOT_RETRY_SEND) = (60000, 500): the first delay is longer than the max",
+                "retry.outbox_attempts (VK_BOT_OUTBOX_ATTEMPTS) = 0: should be at least 1",
+                "http.stall_timeout (VK_BOT_STALL_TIMEOUT) = 30: should be at least 60",
+                "log.format (VK_BOT_LOG_FORMAT) = \"xml\": should be one of text, json",
+                "routing.chat (VK_BOT_CHAT) is not set",
+            ],
+            errors.0
+        );
+    }
+
+    #[test]
+    fn callback_needs_secret() {
+        let line = "--vk.token t --vk.group 1 --routing.chat 1 --source.mode callback";
+        let errors = load_with("", |_| None, line).unwrap_err();
+        assert_eq!(
+            vec!["http.callback_secret (VK_BOT_CALLBACK_SECRET) is not set"],
+            errors.0
+        );
+        let line = format!("{} --http.callback_secret s", line);
+        assert!(load_with("", |_| None, &line).is_ok());
+    }
+
+    #[test]
+    fn inline_settings() {
+        let (_, settings) = load_with(
+            r#"
+            [vk]
+            token = "t"
+            group = 1
+            [[routing.routes]]
+            events = ["board_post"]
+            topics = [10]
+            peer_ids = [1, 2]
+            [routing.filters.2]
+            exclude_keywords = ["draft"]
+            [templates]
+            text_length = 200
+            [templates.chats.2]
+            board_post = "{text}"
+            "#,
+            |_| None,
+            "",
+        )
+        .unwrap();
+        assert_eq!(vec![1, 2], settings.routes.destinations(&board(10, "text")));
+        assert_eq!(vec![1], settings.routes.destinations(&board(10, "draft")));
+        assert!(settings.routes.destinations(&board(11, "text")).is_empty());
+        assert_eq!(200, settings.templates.text_length(1));
+        assert!(settings.templates.get(2, EventKind::BoardPost).is_some());
+    }
+
+    #[test]
+    fn inline_errors() {
+        let errors = load_with(
+            "[vk]\ntoken = \"t\"\ngroup = 1\n[[routing.routes]]\npeer_ids = []\n[templates]\nboard_post = \"{nope}\"",
+            |_| None,
+            "",
+        )
+        .unwrap_err();
+        assert_eq!(2, errors.0.len(), "{:?}", errors.0);
+        assert!(errors.0[0].starts_with("[templates]"), "{}", errors.0[0]);
+        assert!(errors.0[1].starts_with("route without peer_ids"));
+    }
+
+    #[test]
+    fn settings_errors() {
+        let errors = load_with(
+            "",
+            |_| None,
+            "--vk.token t --vk.group 1 --routing.file no-routes.json --templates.file no-templates.json",
+        )
+        .unwrap_err();
+        assert_eq!(2, errors.0.len(), "{:?}", errors.0);
+        assert!(errors.0[0].starts_with("can't read routes"));
+        assert!(errors.0[1].starts_with("can't read templates"));
+    }
+
+    #[test]
+    fn topic_title_needs_service_token() {
+        let toml = "[templates]\nboard_post = \"{topic_title}: {text}\"";
+        let errors =
+            load_with(toml, |_| None, "--vk.token t --vk.group 1 --routing.chat 1").unwrap_err();
+        assert_eq!(
+            vec!["{topic_title} in templates needs vk.service_token (VK_BOT_SERVICE_TOKEN)"],
+            errors.0
+        );
+
+        let line = "--vk.token t --vk.group 1 --routing.chat 1 --vk.service_token s";
+        assert!(load_with(toml, |_| None, line).is_ok());
+    }
 }
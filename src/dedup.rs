@@ -0,0 +1,36 @@
+use crate::long_poll_client::{Event, EventKind};
+use crate::recent::Recent;
+
+/// A new post as `(kind, owner, id)`. The owner is the topic for board
+/// comments and 0 for wall posts of the group.
+pub type EventKey = (EventKind, i64, i64);
+
+pub fn key(event: &Event) -> Option<EventKey> {
+    match event {
+        Event::BoardPost(c) => Some((EventKind::BoardPost, c.topic_id, c.id)),
+        Event::WallPost { id, .. } => Some((EventKind::WallPost, 0, *id)),
+        _ => None,
+    }
+}
+
+/// Remembers the latest forwarded posts, so posts delivered again after a
+/// restart or a `failed: 1` response are not repeated.
+pub type Seen = Recent<EventKey>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_keys() {
+        let mut seen = Seen::new(10);
+        seen.insert((EventKind::BoardPost, 456, 123));
+        seen.insert((EventKind::WallPost, 0, 28));
+        let text = serde_json::to_string(&seen).unwrap();
+        assert_eq!(r#"[["board_post",456,123],["wall_post",0,28]]"#, text);
+        let mut seen: Seen = serde_json::from_str(&text).unwrap();
+        seen.limit(1);
+        assert!(!seen.contains(&(EventKind::BoardPost, 456, 123)));
+        assert!(seen.contains(&(EventKind::WallPost, 0, 28)));
+    }
+}
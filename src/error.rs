@@ -1,10 +1,117 @@
-use simple_error::SimpleError;
+use crate::mask_secret;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 
-pub type Error = simple_error::SimpleError;
-pub type SimpleResult<T> = simple_error::SimpleResult<T>;
+pub type SimpleResult<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// VK answered with an `error` object.
+    Api(ApiError),
+    /// The request did not reach VK or got an unexpected HTTP status.
+    Transport {
+        message: String,
+        status: Option<u16>,
+    },
+    Other(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub method: String,
+    pub error_code: u64,
+    pub error_msg: String,
+    pub request_params: Vec<RequestParam>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestParam {
+    pub key: String,
+    pub value: String,
+}
+
+/// What the caller should do about an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// Temporary failure, the same request may succeed later.
+    Retryable,
+    /// VK asks to slow down, the request should be repeated after a pause.
+    RateLimit,
+    /// The request itself is wrong, repeating it will not help.
+    Rejected,
+    /// The bot can't work at all, e.g. the token is invalid.
+    Fatal,
+}
+
+impl Error {
+    pub fn new<S: Into<String>>(message: S) -> Error {
+        Error::Other(message.into())
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Api(e) => e.class(),
+            Error::Transport {
+                status: Some(429), ..
+            } => ErrorClass::RateLimit,
+            Error::Transport { .. } | Error::Other(_) => ErrorClass::Retryable,
+        }
+    }
+}
+
+impl ApiError {
+    pub fn class(&self) -> ErrorClass {
+        match self.error_code {
+            // unknown error, internal server error
+            1 | 10 => ErrorClass::Retryable,
+            // too many requests per second, flood control, too many same requests
+            6 | 9 | 29 => ErrorClass::RateLimit,
+            // authorization failed
+            5 => ErrorClass::Fatal,
+            // group authorization failed, the token can't get events
+            27 if self.method == "groups.getLongPollServer" => ErrorClass::Fatal,
+            // the rest, like permission denied in a chat which removed the bot,
+            // only fails this call
+            _ => ErrorClass::Rejected,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api(e) => e.fmt(f),
+            Error::Transport {
+                message,
+                status: Some(status),
+            } => write!(f, "{}, status {}", message, status),
+            Error::Transport { message, .. } | Error::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "got error {} <{}> from {}",
+            self.error_code, self.error_msg, self.method
+        )?;
+        for p in &self.request_params {
+            if p.key == "access_token" {
+                write!(f, ", {}={}", p.key, mask_secret::mask(&p.value))?;
+            } else {
+                write!(f, ", {}={}", p.key, p.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
 
 pub trait IntoSimpleError {
-    fn wrap(&self, message: &str) -> SimpleError;
+    fn wrap(&self, message: &str) -> Error;
 }
 
 impl<T> IntoSimpleError for T
@@ -16,8 +123,8 @@ where
     }
 }
 
-fn new<T: std::fmt::Display>(message: &str, cause: &T) -> Error {
-    SimpleError::new(format!("{}, {}", message, cause))
+fn new<T: fmt::Display>(message: &str, cause: &T) -> Error {
+    Error::new(format!("{}, {}", message, cause))
 }
 
 pub trait IntoSimpleResult<T> {
@@ -32,3 +139,54 @@ impl<T> IntoSimpleResult<T> for tokio::io::Result<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn api(error_code: u64) -> Error {
+        api_method("messages.send", error_code)
+    }
+
+    fn api_method(method: &str, error_code: u64) -> Error {
+        Error::Api(ApiError {
+            method: method.to_owned(),
+            error_code,
+            error_msg: "msg".to_owned(),
+            request_params: vec![RequestParam {
+                key: "access_token".to_owned(),
+                value: "1234567".to_owned(),
+            }],
+        })
+    }
+
+    #[test]
+    fn classify() {
+        assert_eq!(ErrorClass::Retryable, api(10).class());
+        assert_eq!(ErrorClass::RateLimit, api(6).class());
+        assert_eq!(ErrorClass::Fatal, api(5).class());
+        assert_eq!(ErrorClass::Rejected, api(7).class());
+        assert_eq!(ErrorClass::Rejected, api(15).class());
+        assert_eq!(ErrorClass::Rejected, api(27).class());
+        assert_eq!(
+            ErrorClass::Fatal,
+            api_method("groups.getLongPollServer", 27).class()
+        );
+        assert_eq!(ErrorClass::Rejected, api(100).class());
+        assert_eq!(ErrorClass::Retryable, Error::new("other").class());
+        let status = |s| Error::Transport {
+            message: "".to_owned(),
+            status: Some(s),
+        };
+        assert_eq!(ErrorClass::RateLimit, status(429).class());
+        assert_eq!(ErrorClass::Retryable, status(502).class());
+    }
+
+    #[test]
+    fn display_masks_token() {
+        assert_eq!(
+            "got error 6 <msg> from messages.send, access_token=12***67",
+            api(6).to_string()
+        );
+    }
+}
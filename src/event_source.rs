@@ -0,0 +1,197 @@
+use crate::catch_up;
+use crate::client::{Client, ServerConfig};
+use crate::error::*;
+use crate::health::health;
+use crate::long_poll_client::{self, get_events, Event};
+use crate::metrics::metrics;
+use crate::state::State;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Where the worker takes events from. A source keeps its position in
+/// `State`, so it is saved together with the rest of the state.
+pub trait EventSource {
+    /// Waits for the next batch of events. `None` means the source is over.
+    fn next<'a>(
+        &'a mut self,
+        client: &'a Client,
+        state: &'a mut State,
+    ) -> impl Future<Output = SimpleResult<Option<Vec<Event>>>> + Send + 'a;
+}
+
+/// Events from the Bots Long Poll API. Posts missed while the bot was down
+/// come first, at start and after the server is refreshed.
+pub struct LongPoll {
+    raw_client: reqwest::Client,
+    catch_up: bool,
+}
+
+impl LongPoll {
+    pub fn new(client: &Client) -> LongPoll {
+        LongPoll {
+            raw_client: client.raw_client(),
+            catch_up: true,
+        }
+    }
+}
+
+impl EventSource for LongPoll {
+    async fn next(
+        &mut self,
+        client: &Client,
+        state: &mut State,
+    ) -> SimpleResult<Option<Vec<Event>>> {
+        if self.catch_up {
+            // cleared only when done, as the worker drops this future on
+            // a retry or a reload and asks again
+            let events = catch_up::missed(client, state).await;
+            self.catch_up = false;
+            if !events.is_empty() {
+                return Ok(Some(events));
+            }
+        }
+        let server = match &mut state.server {
+            Some(server) => server,
+            None => {
+                state.server = Some(client.long_poll_config().await?);
+                return Ok(Some(vec![]));
+            }
+        };
+        let result = get_events(&self.raw_client, server).await?;
+        health().set_ready();
+        if let Some(ts) = &result.ts {
+            server.ts = ts.clone();
+        }
+        if result.refresh_all || result.refresh_key {
+            refresh(client, &mut state.server, result.refresh_all).await?;
+            self.catch_up = true;
+        }
+        Ok(Some(result.events))
+    }
+}
+
+/// Gets a new key, or a new server with the current ts after losing events.
+async fn refresh(
+    client: &Client,
+    server: &mut Option<ServerConfig>,
+    refresh_all: bool,
+) -> SimpleResult<()> {
+    let kind = if refresh_all { "all" } else { "key" };
+    metrics().refreshes.with_label_values(&[kind]).inc();
+    let new_config = client.long_poll_config().await?;
+    match server {
+        Some(server) if !refresh_all => server.key = new_config.key,
+        _ => *server = Some(new_config),
+    }
+    Ok(())
+}
+
+/// Recorded long poll responses, one per line.
+pub struct Replay {
+    batches: VecDeque<Vec<Event>>,
+}
+
+impl Replay {
+    pub async fn from_file(file_name: &str) -> SimpleResult<Replay> {
+        let text = tokio::fs::read_to_string(file_name)
+            .await
+            .wrap_err("can't read replay file")?;
+        Replay::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> SimpleResult<Replay> {
+        let mut batches = VecDeque::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            batches.push_back(long_poll_client::parse(line)?.events);
+        }
+        Ok(Replay { batches })
+    }
+}
+
+impl EventSource for Replay {
+    async fn next(&mut self, _: &Client, _: &mut State) -> SimpleResult<Option<Vec<Event>>> {
+        Ok(self.batches.pop_front())
+    }
+}
+
+/// Events sent by another task, like the Callback API server. Gives an empty
+/// batch when there are no events for a while, so the worker loop goes on.
+pub struct Channel {
+    events: Receiver<Vec<Event>>,
+}
+
+impl Channel {
+    pub fn new(events: Receiver<Vec<Event>>) -> Channel {
+        Channel { events }
+    }
+}
+
+impl EventSource for Channel {
+    async fn next(&mut self, _: &Client, _: &mut State) -> SimpleResult<Option<Vec<Event>>> {
+        match timeout(IDLE, self.events.recv()).await {
+            Ok(events) => Ok(events),
+            Err(_) => Ok(Some(vec![])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn client() -> Client {
+        Client::new("token".to_owned(), 1, 20)
+    }
+
+    #[tokio::test]
+    async fn replay() {
+        let mut replay = Replay::parse(
+            r#"{"ts":"4","updates":[{"type":"wall_post_new","object":{"id":28},"group_id":1}]}
+
+{"failed":1,"ts":5}
+"#,
+        )
+        .unwrap();
+        let mut state = State::new(None);
+        let events = replay.next(&client(), &mut state).await.unwrap().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [Event::WallPost { id: 28, .. }]
+        ));
+        let events = replay.next(&client(), &mut state).await.unwrap().unwrap();
+        assert!(events.is_empty());
+        assert!(replay.next(&client(), &mut state).await.unwrap().is_none());
+        assert!(Replay::parse("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn catch_up_after_cancel() {
+        // accepts requests but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let client = client().with_url(url);
+        let mut source = LongPoll::new(&client);
+        let mut state = State::new(None);
+        state.last_wall_post = 1;
+        let next = timeout(Duration::from_millis(50), source.next(&client, &mut state)).await;
+        assert!(next.is_err());
+        assert!(source.catch_up);
+    }
+
+    #[tokio::test]
+    async fn channel_closes() {
+        let (tx, rx) = channel(1);
+        let mut source = Channel::new(rx);
+        let mut state = State::new(None);
+        tx.send(vec![]).await.unwrap();
+        drop(tx);
+        assert!(source.next(&client(), &mut state).await.unwrap().is_some());
+        assert!(source.next(&client(), &mut state).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::error::*;
+use serde_json::{Map, Value};
+
+/// VK runs at most 25 API calls in one `execute` request.
+pub const MAX_CALLS: usize = 25;
+
+/// One API call inside an `execute` batch. `v` and `access_token` are
+/// taken from the `execute` request itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiCall {
+    pub method: String,
+    params: Map<String, Value>,
+}
+
+impl ApiCall {
+    pub fn new(method: &str) -> ApiCall {
+        ApiCall {
+            method: method.to_string(),
+            params: Map::new(),
+        }
+    }
+
+    pub fn param<V: ToString>(mut self, key: &str, value: V) -> ApiCall {
+        self.params
+            .insert(key.to_string(), Value::String(value.to_string()));
+        self
+    }
+}
+
+/// Builds VKScript code that calls every method and returns the results
+/// as an array in the same order.
+pub fn script(calls: &[ApiCall]) -> String {
+    let calls: Vec<String> = calls
+        .iter()
+        .map(|c| {
+            let params = Value::Object(c.params.clone());
+            format!("API.{}({})", c.method, params)
+        })
+        .collect();
+    format!("return [{}];", calls.join(","))
+}
+
+/// Matches the results of `execute` with its calls. A failed call returns
+/// `false`, and its error is the next one in `errors`.
+pub fn unpack(
+    calls: &[ApiCall],
+    results: Vec<Value>,
+    errors: Vec<ApiError>,
+) -> Vec<SimpleResult<Value>> {
+    let mut results = results.into_iter();
+    let mut errors = errors.into_iter();
+    calls
+        .iter()
+        .map(|call| match results.next() {
+            Some(Value::Bool(false)) => Err(match errors.next() {
+                Some(e) => Error::Api(e),
+                None => Error::new(format!("{} failed in execute", call.method)),
+            }),
+            Some(value) => Ok(value),
+            None => Err(Error::new(format!(
+                "no result for {} in execute",
+                call.method
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn calls() -> Vec<ApiCall> {
+        vec![
+            ApiCall::new("messages.send")
+                .param("peer_ids", 2000000001)
+                .param("message", "say \"hi\""),
+            ApiCall::new("users.get").param("user_ids", "1,2"),
+        ]
+    }
+
+    fn error(method: &str) -> ApiError {
+        ApiError {
+            method: method.to_owned(),
+            error_code: 100,
+            error_msg: "bad".to_owned(),
+            request_params: vec![],
+        }
+    }
+
+    #[test]
+    fn build_script() {
+        assert_eq!(
+            r#"return [API.messages.send({"message":"say \"hi\"","peer_ids":"2000000001"}),API.users.get({"user_ids":"1,2"})];"#,
+            script(&calls())
+        );
+    }
+
+    #[test]
+    fn unpack_results() {
+        let r = unpack(&calls(), vec![json!([1]), json!([{"id": 1}])], vec![]);
+        assert_eq!(vec![Ok(json!([1])), Ok(json!([{"id": 1}]))], r);
+    }
+
+    #[test]
+    fn unpack_errors() {
+        let r = unpack(&calls(), vec![json!(false)], vec![error("messages.send")]);
+        assert_eq!(Err(Error::Api(error("messages.send"))), r[0]);
+        assert!(r[1].is_err());
+    }
+}
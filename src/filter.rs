@@ -0,0 +1,133 @@
+use crate::long_poll_client::Event;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+
+/// Rules for board posts sent to one chat. Empty lists allow anything.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filter {
+    /// The text must contain one of the keywords or match one of the
+    /// patterns, if any are set.
+    keywords: Vec<String>,
+    patterns: Vec<Pattern>,
+    /// The text must contain none of these.
+    exclude_keywords: Vec<String>,
+    exclude_patterns: Vec<Pattern>,
+    allow_authors: Vec<i64>,
+    deny_authors: Vec<i64>,
+    allow_topics: Vec<i64>,
+    deny_topics: Vec<i64>,
+    /// In characters.
+    min_length: usize,
+}
+
+#[derive(Debug)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Pattern, D::Error> {
+        let s = String::deserialize(d)?;
+        Regex::new(&s).map(Pattern).map_err(de::Error::custom)
+    }
+}
+
+fn allowed(id: i64, allow: &[i64], deny: &[i64]) -> bool {
+    (allow.is_empty() || allow.contains(&id)) && !deny.contains(&id)
+}
+
+fn contains_any(text: &str, keywords: &[String]) -> bool {
+    keywords.iter().any(|k| text.contains(&k.to_lowercase()))
+}
+
+fn matches_any(text: &str, patterns: &[Pattern]) -> bool {
+    patterns.iter().any(|p| p.0.is_match(text))
+}
+
+impl Filter {
+    /// Checks new board posts, other events always pass. Keywords are
+    /// case-insensitive, patterns are regular expressions as they are.
+    pub fn allows(&self, event: &Event) -> bool {
+        let (from_id, topic_id, text) = match event {
+            Event::BoardPost(c) => (c.from_id, c.topic_id, c.text.as_str()),
+            _ => return true,
+        };
+        if !allowed(from_id, &self.allow_authors, &self.deny_authors)
+            || !allowed(topic_id, &self.allow_topics, &self.deny_topics)
+            || text.chars().count() < self.min_length
+        {
+            return false;
+        }
+        let lowercase = text.to_lowercase();
+        if contains_any(&lowercase, &self.exclude_keywords)
+            || matches_any(text, &self.exclude_patterns)
+        {
+            return false;
+        }
+        if self.keywords.is_empty() && self.patterns.is_empty() {
+            return true;
+        }
+        contains_any(&lowercase, &self.keywords) || matches_any(text, &self.patterns)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::long_poll_client::BoardComment;
+
+    fn board(from_id: i64, topic_id: i64, text: &str) -> Event {
+        Event::BoardPost(BoardComment {
+            from_id,
+            text: text.to_owned(),
+            topic_id,
+            id: 1,
+            date: 0,
+            attachments: vec![],
+        })
+    }
+
+    #[test]
+    fn board_posts() {
+        let filter: Filter = serde_json::from_str(
+            r#"{
+                "keywords": ["Release"],
+                "patterns": ["v\\d+\\.\\d+"],
+                "exclude_keywords": ["spam"],
+                "deny_authors": [666],
+                "allow_topics": [10, 11],
+                "deny_topics": [11],
+                "min_length": 5
+            }"#,
+        )
+        .unwrap();
+        assert!(filter.allows(&board(1, 10, "new RELEASE")));
+        assert!(filter.allows(&board(1, 10, "see v1.2")));
+        assert!(!filter.allows(&board(1, 10, "nothing new")));
+        assert!(!filter.allows(&board(1, 10, "release spam")));
+        assert!(!filter.allows(&board(666, 10, "new release")));
+        assert!(!filter.allows(&board(1, 11, "new release")));
+        assert!(!filter.allows(&board(1, 12, "new release")));
+        assert!(!filter.allows(&board(1, 10, "v1.2")));
+
+        let wall = Event::WallPost {
+            id: 1,
+            text: "spam".to_owned(),
+            date: 0,
+        };
+        assert!(filter.allows(&wall));
+        assert!(Filter::default().allows(&board(1, 1, "")));
+    }
+
+    #[test]
+    fn bad_pattern() {
+        let e = serde_json::from_str::<Filter>(r#"{"patterns": ["("]}"#).unwrap_err();
+        assert!(e.to_string().contains("regex parse error"), "{}", e);
+        assert!(serde_json::from_str::<Filter>(r#"{"min_lenght": 5}"#).is_err());
+    }
+}
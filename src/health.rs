@@ -0,0 +1,78 @@
+use crate::clock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Readiness and liveness of the bot, shown on `/readyz` and `/healthz`.
+pub struct Health {
+    ready: AtomicBool,
+    /// Unix time when the worker loop last went round.
+    beat: AtomicU64,
+    /// Seconds without a beat after which the worker is considered stalled.
+    stall_timeout: u64,
+}
+
+pub const DEFAULT_STALL_TIMEOUT: u64 = 10 * 60;
+/// A long poll request alone takes up to 25 seconds without a beat, and
+/// catching up after it takes some more.
+pub const MIN_STALL_TIMEOUT: u64 = 60;
+
+static HEALTH: OnceLock<Health> = OnceLock::new();
+
+/// Sets the stall timeout, must be called before `health()`.
+pub fn init(stall_timeout: u64) {
+    if HEALTH.set(Health::new(stall_timeout)).is_err() {
+        panic!("health is already initialized");
+    }
+}
+
+pub fn health() -> &'static Health {
+    HEALTH.get_or_init(|| Health::new(DEFAULT_STALL_TIMEOUT))
+}
+
+impl Health {
+    fn new(stall_timeout: u64) -> Health {
+        Health {
+            ready: AtomicBool::new(false),
+            beat: AtomicU64::new(0),
+            stall_timeout,
+        }
+    }
+
+    /// Called once the bot gets events.
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn beat(&self) {
+        self.beat.store(clock::now(), Ordering::Relaxed)
+    }
+
+    /// Alive until the worker loop stalls. A worker which hasn't started yet
+    /// is alive too.
+    pub fn is_alive(&self, now: u64) -> bool {
+        let beat = self.beat.load(Ordering::Relaxed);
+        beat == 0 || now.saturating_sub(beat) <= self.stall_timeout
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stalled() {
+        let h = Health::new(60);
+        assert!(h.is_alive(clock::now()));
+        assert!(!h.is_ready());
+        h.beat();
+        h.set_ready();
+        let now = clock::now();
+        assert!(h.is_alive(now + 60));
+        assert!(!h.is_alive(now + 61));
+        assert!(h.is_ready());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::clock;
+use crate::error::*;
+use crate::health::health;
+use crate::long_poll_client::{parse_update, Event};
+use crate::metrics::metrics;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, error, warn};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Settings of the Callback API server, as set in the community.
+pub struct Callback {
+    pub path: String,
+    pub group_id: u64,
+    /// Code returned on the `confirmation` request.
+    pub confirmation: String,
+    /// Secret key from the community settings, sent with every request.
+    pub secret: String,
+    pub events: Sender<Vec<Event>>,
+}
+
+/// Starts the server with `/metrics`, `/healthz`, `/readyz` and the
+/// callback, if it is set, and
+/// returns its address, which is useful when binding to port 0. The server
+/// stops on cancellation.
+pub fn serve(
+    addr: SocketAddr,
+    callback: Option<Callback>,
+    ct: CancellationToken,
+) -> SimpleResult<(SocketAddr, JoinHandle<()>)> {
+    let callback = Arc::new(callback);
+    let make_service = make_service_fn(move |_| {
+        let callback = callback.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(callback.clone(), req))) }
+    });
+    let server = Server::try_bind(&addr)
+        .map_err(|e| e.wrap("can't bind http server"))?
+        .serve(make_service);
+    let addr = server.local_addr();
+    let server = server.with_graceful_shutdown(async move { ct.cancelled().await });
+    let handle = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("http server failed: {}", e);
+        }
+    });
+    Ok((addr, handle))
+}
+
+async fn handle(
+    callback: Arc<Option<Callback>>,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    match (req.method(), req.uri().path(), callback.as_ref()) {
+        (&Method::GET, "/metrics", _) => {
+            let mut r = reply(StatusCode::OK, &metrics().render());
+            r.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            Ok(r)
+        }
+        (&Method::GET, "/healthz", _) => Ok(if health().is_alive(clock::now()) {
+            reply(StatusCode::OK, "ok")
+        } else {
+            reply(StatusCode::SERVICE_UNAVAILABLE, "stalled")
+        }),
+        (&Method::GET, "/readyz", _) => Ok(if health().is_ready() {
+            reply(StatusCode::OK, "ready")
+        } else {
+            reply(StatusCode::SERVICE_UNAVAILABLE, "not ready")
+        }),
+        (&Method::POST, path, Some(callback)) if path == callback.path => {
+            Ok(handle_callback(callback, req).await)
+        }
+        _ => Ok(reply(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+async fn handle_callback(callback: &Callback, req: Request<Body>) -> Response<Body> {
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("can't read callback request: {}", e);
+            return reply(StatusCode::BAD_REQUEST, "bad request");
+        }
+    };
+    let update: Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("can't parse callback request: {}", e);
+            return reply(StatusCode::BAD_REQUEST, "bad request");
+        }
+    };
+    let secret = update.get("secret").and_then(|s| s.as_str()).unwrap_or("");
+    if !same_secret(secret.as_bytes(), callback.secret.as_bytes()) {
+        warn!("callback request with a wrong secret");
+        return reply(StatusCode::FORBIDDEN, "forbidden");
+    }
+    if update.get("group_id").and_then(|id| id.as_u64()) != Some(callback.group_id) {
+        warn!("callback request for another group");
+        return reply(StatusCode::FORBIDDEN, "forbidden");
+    }
+    if update.get("type").and_then(|t| t.as_str()) == Some("confirmation") {
+        return reply(StatusCode::OK, &callback.confirmation);
+    }
+    let event = match parse_update(update) {
+        Ok(e) => e,
+        Err(e) => {
+            // VK repeats updates which are not answered with "ok"
+            error!("{}", e);
+            return reply(StatusCode::OK, "ok");
+        }
+    };
+    if let Some(event) = event {
+        if callback.events.send(vec![event]).await.is_err() {
+            return reply(StatusCode::INTERNAL_SERVER_ERROR, "stopped");
+        }
+    } else {
+        debug!("skip unknown callback update");
+    }
+    reply(StatusCode::OK, "ok")
+}
+
+/// Takes the same time wherever the secrets differ, so that it can't be
+/// guessed byte by byte.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn reply(status: StatusCode, text: &str) -> Response<Body> {
+    let mut r = Response::new(Body::from(text.to_string()));
+    *r.status_mut() = status;
+    r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    fn start(ct: &CancellationToken) -> (String, Receiver<Vec<Event>>) {
+        let (tx, rx) = channel(10);
+        let callback = Callback {
+            path: "/callback".to_owned(),
+            group_id: 123456,
+            confirmation: "code".to_owned(),
+            secret: "secret".to_owned(),
+            events: tx,
+        };
+        let (addr, _) = serve(([127, 0, 0, 1], 0).into(), Some(callback), ct.clone()).unwrap();
+        (format!("http://{}/callback", addr), rx)
+    }
+
+    async fn post(url: &str, body: &str) -> (u16, String) {
+        let r = reqwest::Client::new()
+            .post(url)
+            .body(body.to_owned())
+            .send()
+            .await
+            .unwrap();
+        (r.status().as_u16(), r.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn confirmation() {
+        let ct = CancellationToken::new();
+        let (url, _rx) = start(&ct);
+        let r = post(
+            &url,
+            r#"{"type":"confirmation","group_id":123456,"secret":"secret"}"#,
+        )
+        .await;
+        assert_eq!((200, "code".to_owned()), r);
+        let r = post(
+            &url,
+            r#"{"type":"confirmation","group_id":1,"secret":"secret"}"#,
+        )
+        .await;
+        assert_eq!(403, r.0);
+        ct.cancel();
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let ct = CancellationToken::new();
+        let (url, mut rx) = start(&ct);
+        let update = r#"
+{
+   "type":"board_post_new",
+   "object":{"from_id":1000, "text":"some text", "id":123, "topic_id":456},
+   "group_id":123456,
+   "secret":"%"
+}"#;
+        let r = post(&url, &update.replace('%', "wrong")).await;
+        assert_eq!(403, r.0);
+        let r = post(&url, &update.replace(r#","secret":"%""#, "")).await;
+        assert_eq!(403, r.0);
+        let r = post(&url, &update.replace('%', "secret")).await;
+        assert_eq!((200, "ok".to_owned()), r);
+        let r = post(
+            &url,
+            r#"{"type":"group_join","object":{},"group_id":123456,"secret":"secret"}"#,
+        )
+        .await;
+        assert_eq!((200, "ok".to_owned()), r);
+
+        let events = rx.recv().await.unwrap();
+        match events.as_slice() {
+            [Event::BoardPost(c)] => assert_eq!(123, c.id),
+            _ => panic!("unexpected events"),
+        }
+        assert!(rx.try_recv().is_err());
+        ct.cancel();
+    }
+
+    #[test]
+    fn compare_secrets() {
+        assert!(same_secret(b"secret", b"secret"));
+        assert!(!same_secret(b"secret", b"secreT"));
+        assert!(!same_secret(b"secret", b"secret2"));
+        assert!(!same_secret(b"", b"secret"));
+    }
+
+    #[tokio::test]
+    async fn status_pages() {
+        let ct = CancellationToken::new();
+        let (addr, _) = serve(([127, 0, 0, 1], 0).into(), None, ct.clone()).unwrap();
+        let r = reqwest::get(&format!("http://{}/metrics", addr))
+            .await
+            .unwrap();
+        assert_eq!(200, r.status().as_u16());
+        assert!(r.text().await.unwrap().contains("vk_bot_messages_queued"));
+        let r = reqwest::get(&format!("http://{}/healthz", addr))
+            .await
+            .unwrap();
+        assert_eq!(200, r.status().as_u16());
+        let r = post(&format!("http://{}/callback", addr), "{}").await;
+        assert_eq!(404, r.0);
+        ct.cancel();
+    }
+}
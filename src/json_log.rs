@@ -0,0 +1,166 @@
+use crate::clock;
+use crate::mask_secret::mask;
+use log::kv::{self, Key, VisitSource, VisitValue};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use std::io::Write;
+
+/// Logger which writes a JSON object per line to stderr, with the
+/// key-values of the record as fields, like
+/// `{"time":..,"level":"INFO","target":..,"message":..,"peer_id":..}`.
+pub struct JsonLogger {
+    level: LevelFilter,
+    /// Replaced with their masked form wherever they appear.
+    secrets: Vec<String>,
+}
+
+/// Installs the logger with the level from `RUST_LOG`, `info` by default.
+pub fn init(secrets: Vec<String>) {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    log::set_boxed_logger(Box::new(JsonLogger::new(level, secrets)))
+        .expect("logger is already set");
+    log::set_max_level(level);
+}
+
+impl JsonLogger {
+    pub fn new(level: LevelFilter, secrets: Vec<String>) -> JsonLogger {
+        let secrets = secrets.into_iter().filter(|s| !s.is_empty()).collect();
+        JsonLogger { level, secrets }
+    }
+
+    fn redact(&self, s: String) -> String {
+        self.secrets
+            .iter()
+            .fold(s, |s, secret| s.replace(secret.as_str(), &mask(secret)))
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let mut line = Map::new();
+        line.insert("time".into(), clock::now().into());
+        line.insert("level".into(), record.level().as_str().into());
+        line.insert("target".into(), record.target().into());
+        line.insert(
+            "message".into(),
+            self.redact(record.args().to_string()).into(),
+        );
+        let mut fields = Fields {
+            logger: self,
+            line: &mut line,
+        };
+        // a visitor that never fails
+        let _ = record.key_values().visit(&mut fields);
+        Value::Object(line).to_string()
+    }
+}
+
+struct Fields<'a> {
+    logger: &'a JsonLogger,
+    line: &'a mut Map<String, Value>,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut field = Field {
+            logger: self.logger,
+            value: Value::Null,
+        };
+        value.visit(&mut field)?;
+        self.line.insert(key.to_string(), field.value);
+        Ok(())
+    }
+}
+
+/// Keeps numbers, booleans and nulls as they are, anything else is a
+/// redacted string.
+struct Field<'a> {
+    logger: &'a JsonLogger,
+    value: Value,
+}
+
+impl<'v> VisitValue<'v> for Field<'_> {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.value = self.logger.redact(value.to_string()).into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.value = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.value = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.value = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.value = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.value = value.into();
+        Ok(())
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = self.format(record);
+        line.push('\n');
+        let _ = std::io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn json_line() {
+        let logger = JsonLogger::new(LevelFilter::Info, vec!["secret-token".to_owned()]);
+        let fields: &[(&str, kv::Value)] = &[
+            ("peer_id", kv::Value::from(2000000001i64)),
+            ("topic_id", kv::Value::null()),
+            (
+                "url",
+                kv::Value::from("https://x/?access_token=secret-token"),
+            ),
+        ];
+        let line = logger.format(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("worker")
+                .args(format_args!("bad token secret-token"))
+                .key_values(&fields)
+                .build(),
+        );
+        let line: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("WARN", line["level"]);
+        assert_eq!("worker", line["target"]);
+        assert_eq!("bad token se********en", line["message"]);
+        assert_eq!(2000000001i64, line["peer_id"]);
+        assert_eq!(Value::Null, line["topic_id"]);
+        assert_eq!("https://x/?access_token=se********en", line["url"]);
+    }
+}
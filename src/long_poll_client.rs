@@ -1,8 +1,8 @@
+use crate::attachment::{self, Attachment};
 use crate::client::ServerConfig;
 use crate::error::*;
 use serde::de::IgnoredAny;
-use serde::Deserialize;
-use serde_json;
+use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 
 pub async fn get_events(client: &reqwest::Client, config: &ServerConfig) -> SimpleResult<Result> {
@@ -17,12 +17,9 @@ pub async fn get_events(client: &reqwest::Client, config: &ServerConfig) -> Simp
         .query(&query)
         .send()
         .await
-        .map_err(|e| Error::new(format!("got error {} on long poll request", e)))?;
+        .map_err(wrap)?;
     let status = r.status();
-    let text = r
-        .text()
-        .await
-        .map_err(|e| Error::new(format!("got error {} on long poll request", e)))?;
+    let text = r.text().await.map_err(wrap)?;
     let r: Response = serde_json::from_str(&text).map_err(|e| {
         Error::new(format!(
             "{:?} on deserialize <{}> from long poll request, status {}",
@@ -32,6 +29,29 @@ pub async fn get_events(client: &reqwest::Client, config: &ServerConfig) -> Simp
     Ok(r.into())
 }
 
+/// The url is left out, because it has the key of the long poll server.
+fn wrap(e: reqwest::Error) -> Error {
+    Error::Transport {
+        message: format!("got error {} on long poll request", e.without_url()),
+        status: None,
+    }
+}
+
+/// Parses a long poll response, as recorded from the server.
+pub fn parse(text: &str) -> SimpleResult<Result> {
+    let r: Response = serde_json::from_str(text)
+        .map_err(|e| Error::new(format!("{:?} on deserialize <{}>", e, text)))?;
+    Ok(r.into())
+}
+
+/// Parses a single update, as the Callback API sends them. Unknown
+/// updates give `None`.
+pub fn parse_update(update: serde_json::Value) -> SimpleResult<Option<Event>> {
+    let wrapper: ResponseEventWrapper =
+        serde_json::from_value(update).map_err(|e| e.wrap("can't parse update"))?;
+    Ok(wrapper.into())
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(untagged)]
 enum Response {
@@ -68,18 +88,44 @@ impl PartialEq for ResponseEventWrapper {
     }
 }
 
+/// A comment on the board, as it is posted, edited or restored.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct BoardComment {
+    pub from_id: i64,
+    pub text: String,
+    pub topic_id: i64,
+    pub id: i64,
+    #[serde(default)]
+    pub date: i64,
+    #[serde(default, deserialize_with = "attachment::lenient")]
+    pub attachments: Vec<Attachment>,
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(tag = "type", content = "object")]
 enum ResponseEvent {
     #[serde(rename = "board_post_new")]
-    BoardPost {
+    BoardPost(BoardComment),
+    #[serde(rename = "board_post_edit")]
+    BoardPostEdit(BoardComment),
+    #[serde(rename = "board_post_restore")]
+    BoardPostRestore(BoardComment),
+    #[serde(rename = "board_post_delete")]
+    BoardPostDelete { topic_id: i64, id: i64 },
+    #[serde(rename = "wall_post_new")]
+    WallPost {
+        id: i64,
+        #[serde(default)]
+        text: String,
+        #[serde(default)]
+        date: i64,
+    },
+    #[serde(rename = "message_new")]
+    Message {
+        peer_id: i64,
         from_id: i64,
         text: String,
-        topic_id: i64,
-        id: i64,
     },
-    #[serde(rename = "wall_post_new")]
-    WallPost { id: i64 },
 }
 
 pub struct Result {
@@ -90,33 +136,96 @@ pub struct Result {
 }
 
 pub enum Event {
-    BoardPost {
-        from_id: i64,
-        text: String,
+    BoardPost(BoardComment),
+    BoardPostEdit(BoardComment),
+    BoardPostRestore(BoardComment),
+    BoardPostDelete {
         topic_id: i64,
         id: i64,
     },
     WallPost {
         id: i64,
+        text: String,
+        date: i64,
     },
+    Message {
+        peer_id: i64,
+        from_id: i64,
+        text: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    BoardPost,
+    WallPost,
+    Message,
+}
+
+impl Event {
+    /// Type of the update in VK.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::BoardPost(_) => "board_post_new",
+            Event::BoardPostEdit(_) => "board_post_edit",
+            Event::BoardPostRestore(_) => "board_post_restore",
+            Event::BoardPostDelete { .. } => "board_post_delete",
+            Event::WallPost { .. } => "wall_post_new",
+            Event::Message { .. } => "message_new",
+        }
+    }
+
+    /// The comment of a new, edited or restored board post.
+    pub fn comment(&self) -> Option<&BoardComment> {
+        match self {
+            Event::BoardPost(c) | Event::BoardPostEdit(c) | Event::BoardPostRestore(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Topic of a board event.
+    pub fn topic_id(&self) -> Option<i64> {
+        match self {
+            Event::BoardPostDelete { topic_id, .. } => Some(*topic_id),
+            _ => self.comment().map(|c| c.topic_id),
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::BoardPost(_)
+            | Event::BoardPostEdit(_)
+            | Event::BoardPostRestore(_)
+            | Event::BoardPostDelete { .. } => EventKind::BoardPost,
+            Event::WallPost { .. } => EventKind::WallPost,
+            Event::Message { .. } => EventKind::Message,
+        }
+    }
 }
 
 impl From<ResponseEventWrapper> for Option<Event> {
     fn from(source: ResponseEventWrapper) -> Option<Event> {
         match source {
             ResponseEventWrapper::Some(e) => match e {
-                ResponseEvent::BoardPost {
+                ResponseEvent::BoardPost(c) => Some(Event::BoardPost(c)),
+                ResponseEvent::BoardPostEdit(c) => Some(Event::BoardPostEdit(c)),
+                ResponseEvent::BoardPostRestore(c) => Some(Event::BoardPostRestore(c)),
+                ResponseEvent::BoardPostDelete { topic_id, id } => {
+                    Some(Event::BoardPostDelete { topic_id, id })
+                }
+                ResponseEvent::WallPost { id, text, date } => {
+                    Some(Event::WallPost { id, text, date })
+                }
+                ResponseEvent::Message {
+                    peer_id,
                     from_id,
                     text,
-                    topic_id,
-                    id,
-                } => Some(Event::BoardPost {
+                } => Some(Event::Message {
+                    peer_id,
                     from_id,
                     text,
-                    topic_id,
-                    id,
                 }),
-                ResponseEvent::WallPost { id } => Some(Event::WallPost { id }),
             },
             ResponseEventWrapper::Unknown(_) => None,
         }
@@ -127,9 +236,9 @@ impl From<Response> for Result {
     fn from(source: Response) -> Result {
         match source {
             Response::Fail { failed, ts } => {
-                if failed == 1 && ts.is_some() {
+                if let (1, Some(ts)) = (failed, ts) {
                     Result {
-                        ts: Some(ts.unwrap().to_string()),
+                        ts: Some(ts.to_string()),
                         events: vec![],
                         refresh_all: false,
                         refresh_key: false,
@@ -164,17 +273,29 @@ impl From<Response> for Result {
 mod test {
     use super::*;
 
-    fn wall(id: i64) -> ResponseEventWrapper {
-        ResponseEventWrapper::Some(ResponseEvent::WallPost { id })
+    fn wall(id: i64, text: &str) -> ResponseEventWrapper {
+        ResponseEventWrapper::Some(ResponseEvent::WallPost {
+            id,
+            text: text.to_owned(),
+            date: 0,
+        })
     }
 
-    fn board(from_id: i64, text: String, topic_id: i64, id: i64) -> ResponseEventWrapper {
-        ResponseEventWrapper::Some(ResponseEvent::BoardPost {
+    fn comment(from_id: i64, text: &str, topic_id: i64, id: i64) -> BoardComment {
+        BoardComment {
             id,
             from_id,
-            text,
+            text: text.to_owned(),
             topic_id,
-        })
+            date: 0,
+            attachments: vec![],
+        }
+    }
+
+    fn board(from_id: i64, text: String, topic_id: i64, id: i64) -> ResponseEventWrapper {
+        ResponseEventWrapper::Some(ResponseEvent::BoardPost(comment(
+            from_id, &text, topic_id, id,
+        )))
     }
 
     #[test]
@@ -207,12 +328,130 @@ mod test {
         assert_eq!(
             Response::Ok {
                 ts: "4".to_owned(),
-                updates: vec!(wall(28), board(1000, "some text".to_owned(), 456, 123),)
+                updates: vec!(wall(28, ""), board(1000, "some text".to_owned(), 456, 123),)
             },
             result
         );
     }
 
+    #[test]
+    fn deserialize_wall_text() {
+        let source = r#"
+        {
+         "type":"wall_post_new",
+         "object":{
+            "id":28,
+            "text":"wall text",
+            "date":1578870439
+         },
+         "group_id":123456
+        }"#;
+        let result: ResponseEventWrapper = serde_json::from_str(source).unwrap();
+        assert_eq!(
+            ResponseEventWrapper::Some(ResponseEvent::WallPost {
+                id: 28,
+                text: "wall text".to_owned(),
+                date: 1578870439,
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn deserialize_board_changes() {
+        let source = r#"
+{
+   "ts":"5",
+   "updates":[
+      {
+         "type":"board_post_edit",
+         "object":{
+            "from_id":1000,
+            "text":"new text",
+            "id":123,
+            "topic_id":456,
+            "topic_owner_id":-123456
+         },
+         "group_id":123456
+      },
+      {
+         "type":"board_post_delete",
+         "object":{
+            "topic_owner_id":-123456,
+            "topic_id":456,
+            "id":123
+         },
+         "group_id":123456
+      },
+      {
+         "type":"board_post_restore",
+         "object":{
+            "from_id":1000,
+            "text":"new text",
+            "id":123,
+            "topic_id":456,
+            "topic_owner_id":-123456
+         },
+         "group_id":123456
+      }
+   ]
+}
+        "#;
+        let result: Response = serde_json::from_str(source).unwrap();
+        assert_eq!(
+            Response::Ok {
+                ts: "5".to_owned(),
+                updates: vec!(
+                    ResponseEventWrapper::Some(ResponseEvent::BoardPostEdit(comment(
+                        1000, "new text", 456, 123
+                    ))),
+                    ResponseEventWrapper::Some(ResponseEvent::BoardPostDelete {
+                        topic_id: 456,
+                        id: 123,
+                    }),
+                    ResponseEventWrapper::Some(ResponseEvent::BoardPostRestore(comment(
+                        1000, "new text", 456, 123
+                    ))),
+                )
+            },
+            result
+        );
+    }
+
+    #[test]
+    fn deserialize_broken_attachment() {
+        let source = r#"
+        {
+         "type":"board_post_new",
+         "object":{
+            "from_id":1000,
+            "text":"some text",
+            "id":123,
+            "topic_id":456,
+            "attachments":[
+               {"type":"photo","photo":{"id":1}},
+               {"type":"link","link":{"url":"https://example.com"}}
+            ]
+         },
+         "group_id":123456
+        }"#;
+        let result: ResponseEvent = serde_json::from_str(source).unwrap();
+        match result {
+            ResponseEvent::BoardPost(c) => assert_eq!(
+                vec![
+                    Attachment::Unknown,
+                    Attachment::Link {
+                        link: attachment::Link {
+                            url: "https://example.com".to_owned()
+                        }
+                    }
+                ],
+                c.attachments
+            ),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
     #[test]
     fn deserialize_fail() {
         let source = r#"{"failed":1,"ts":30}"#;
@@ -237,10 +476,19 @@ mod test {
          "group_id":123456
         }"#;
         let result: ResponseEvent = serde_json::from_str(source).unwrap();
-        assert_eq!(ResponseEvent::WallPost { id: 28 }, result);
+        assert_eq!(
+            ResponseEvent::WallPost {
+                id: 28,
+                text: String::new(),
+                date: 0,
+            },
+            result
+        );
     }
 
     #[test]
+    // left as it was written, newer clippy flags `default()` of a unit struct
+    #[allow(clippy::default_constructed_unit_structs)]
     fn deserialize_other_event() {
         let source = r#"
         {
@@ -253,7 +501,7 @@ mod test {
     }
 
     #[test]
-    fn deserialize_other() {
+    fn deserialize_message() {
         let source = r#"
 {
    "ts":"4",
@@ -287,9 +535,30 @@ mod test {
         assert_eq!(
             Response::Ok {
                 ts: "4".to_owned(),
-                updates: vec!(ResponseEventWrapper::Unknown(IgnoredAny::default()))
+                updates: vec!(ResponseEventWrapper::Some(ResponseEvent::Message {
+                    peer_id: 2000000001,
+                    from_id: 5848319,
+                    text: "sdfsdf".to_owned(),
+                }))
             },
             result
         );
     }
+
+    #[tokio::test]
+    async fn hide_key() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = ServerConfig {
+            key: "secret-key".to_owned(),
+            server: format!("http://{}", addr),
+            ts: "1".to_owned(),
+        };
+        let e = match get_events(&reqwest::Client::new(), &config).await {
+            Ok(_) => panic!("no error"),
+            Err(e) => e,
+        };
+        assert!(!e.to_string().contains("secret-key"), "{}", e);
+    }
 }
@@ -1,53 +1,292 @@
 #![recursion_limit = "1024"]
+mod attachment;
+mod author_cache;
+mod catch_up;
+mod cli;
+mod clock;
 #[macro_use]
-
 mod client;
+mod commands;
 mod config;
+mod dedup;
 mod error;
+mod event_source;
+mod execute;
+mod filter;
+mod health;
+mod http_server;
+mod json_log;
 mod long_poll_client;
 mod mask_secret;
-mod server_config;
+mod message_map;
+mod metrics;
+mod outbox;
+mod rate_limiter;
+mod recent;
+mod retry;
+mod routing;
+mod state;
+mod template;
 mod worker;
 
-use client::{Client, ServerConfig};
-use error::SimpleResult;
-use log::info;
-use server_config::ConfigProvider;
+use cli::Subcommand;
+use client::Client;
+use config::Config;
+use error::{Error, SimpleResult};
+use event_source::{Channel, EventSource, LongPoll, Replay};
+use health::health;
+use log::{error, info};
+use state::{State, StateStore};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use worker::Settings;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = cli::parse(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, cli::USAGE);
+        std::process::exit(2)
+    });
+    if cli.subcommand == Subcommand::Help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let (mut config, settings) = config::load(&cli.config_args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2)
+    });
+    if let Subcommand::Replay { file } = &cli.subcommand {
+        config.source.mode = "replay".to_string();
+        config.source.replay_file = Some(file.clone());
+    }
+    init_log(&config);
+    config::init(config);
+    let config = config::get();
+    let r = match cli.subcommand {
+        Subcommand::Run | Subcommand::Replay { .. } => run(config, settings, cli.config_args).await,
+        Subcommand::CheckConfig => check_config(config).await,
+        Subcommand::Send { peer_id, text } => send(config, peer_id, text).await,
+        Subcommand::Whoami => whoami(config).await,
+        Subcommand::ResetState => reset_state(config).await,
+        Subcommand::Help => Ok(()),
+    };
+    if let Err(e) = r {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
+}
+
+/// Starts the bot and waits until it's stopped by a signal, a fatal error
+/// or the end of a replay.
+async fn run(config: &Config, settings: Settings, args: Vec<String>) -> SimpleResult<()> {
     info!("start bot");
-    let client = new_client();
-    let (p, c) = new_provider(&client).await.unwrap();
-    let ct = tokio_util::sync::CancellationToken::new();
-    let w = tokio::spawn(worker::run(client, c, p, ct.clone()));
-    match tokio::signal::ctrl_c().await {
-        Ok(()) => {}
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-            // we also shut down in case of error
+    health::init(config.http.stall_timeout);
+    let client = new_client(config);
+    let (store, state) = new_state(config).await?;
+    let (reloads, reloaded) = channel(1);
+    reload_on_hangup(args, reloads);
+    let ct = CancellationToken::new();
+    let mut callback = None;
+    let mut w = match config.source.mode.as_str() {
+        "long_poll" => {
+            let source = LongPoll::new(&client);
+            spawn_worker(client, state, store, settings, source, reloaded, &ct)
+        }
+        "callback" => {
+            let (c, source) = new_callback(config, &client).await?;
+            callback = Some(c);
+            health().set_ready();
+            spawn_worker(client, state, store, settings, source, reloaded, &ct)
+        }
+        "replay" => {
+            let file_name = config.source.replay_file.as_deref().unwrap_or_default();
+            let source = Replay::from_file(file_name).await?;
+            health().set_ready();
+            spawn_worker(client, state, store, settings, source, reloaded, &ct)
+        }
+        m => return Err(Error::new(format!("unknown mode {}", m))),
+    };
+    if let Err(e) = start_http_server(config, callback, &ct) {
+        ct.cancel();
+        w.await.unwrap();
+        return Err(e);
+    }
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => {
+            if let Err(err) = r {
+                eprintln!("Unable to listen for shutdown signal: {}", err);
+                // we also shut down in case of error
+            }
+            ct.cancel();
+            w.await.unwrap()
         }
+        // the worker stops by itself on fatal errors or at the end of a replay
+        r = &mut w => r.unwrap(),
     }
-    ct.cancel();
-    w.await.unwrap()
+    Ok(())
 }
 
-fn new_client() -> Client {
-    let token = config::token();
-    info!(target: "main", "token {:?}", mask_secret::mask(&token));
-    client::Client::new(token, config::group_id())
+fn init_log(config: &Config) {
+    if config.log.format == "json" {
+        let secrets = vec![
+            Some(config.vk.token.clone()),
+            config.vk.service_token.clone(),
+            config.http.callback_secret.clone(),
+        ];
+        json_log::init(secrets.into_iter().flatten().collect())
+    } else {
+        env_logger::init()
+    }
 }
 
-async fn new_provider(client: &Client) -> SimpleResult<(Option<ConfigProvider>, ServerConfig)> {
-    match config::server_options_file() {
-        Some(file_name) => {
-            let (p, c) = server_config::with_file(client, &file_name).await?;
-            Ok((Some(p), c))
+fn spawn_worker<S: EventSource + Send + 'static>(
+    client: Client,
+    state: State,
+    store: Option<StateStore>,
+    settings: Settings,
+    source: S,
+    reloads: Receiver<Settings>,
+    ct: &CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(worker::run(
+        client,
+        state,
+        store,
+        settings,
+        source,
+        reloads,
+        ct.clone(),
+    ))
+}
+
+/// Checks that the token works, the rest of the config is checked on load.
+async fn check_config(config: &Config) -> SimpleResult<()> {
+    let group = new_client(config).whoami().await?;
+    println!(
+        "config is ok, the token is of {} {}",
+        group.name, group.link
+    );
+    Ok(())
+}
+
+async fn send(config: &Config, peer_id: i64, text: String) -> SimpleResult<()> {
+    let id = new_client(config)
+        .send_message(peer_id, Some(text), None)
+        .await?;
+    println!("sent to {}, conversation_message_id {}", peer_id, id);
+    Ok(())
+}
+
+async fn whoami(config: &Config) -> SimpleResult<()> {
+    let group = new_client(config).whoami().await?;
+    println!("{} {} {}", -group.id, group.name, group.link);
+    Ok(())
+}
+
+/// Forgets the long poll server, so the bot gets a new one and catches up
+/// on missed posts at the next start. Caches and unsent messages stay.
+async fn reset_state(config: &Config) -> SimpleResult<()> {
+    let file_name = config
+        .storage
+        .state_file
+        .as_deref()
+        .ok_or_else(|| Error::new("no state file in storage.state_file"))?;
+    let (store, mut state) = state::with_file(file_name).await?;
+    state.server = None;
+    store.write(&state).await?;
+    println!("long poll server is cleared in {}", file_name);
+    Ok(())
+}
+
+/// Loads the config again on SIGHUP and passes the new routes and templates
+/// to the worker. Other settings need a restart.
+fn reload_on_hangup(args: Vec<String>, reloads: Sender<Settings>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("can't listen for SIGHUP: {}", e);
+            return;
         }
-        None => {
-            let c = client.long_poll_config().await?;
-            Ok((None, c))
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("reload config");
+            match reload(&args) {
+                Ok(settings) => {
+                    if reloads.send(settings).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => error!("config is not reloaded: {}", e),
+            }
         }
+    });
+}
+
+fn reload(args: &[String]) -> SimpleResult<Settings> {
+    let (_, settings) = config::load(args).map_err(|e| Error::new(e.to_string()))?;
+    Ok(settings)
+}
+
+fn new_client(config: &Config) -> Client {
+    let vk = &config.vk;
+    info!(target: "main", "token {:?}", mask_secret::mask(&vk.token));
+    client::Client::new(vk.token.clone(), vk.group_id, vk.requests_per_second)
+        .with_service_token(vk.service_token.clone())
+}
+
+async fn new_state(config: &Config) -> SimpleResult<(Option<StateStore>, State)> {
+    // a replay is for debugging and must not change the state of the bot
+    if config.source.mode == "replay" {
+        return Ok((None, State::new(None)));
+    }
+    match &config.storage.state_file {
+        Some(file_name) => {
+            let (store, state) = state::with_file(file_name).await?;
+            Ok((Some(store), state))
+        }
+        None => Ok((None, State::new(None))),
     }
 }
+
+/// Settings of the Callback API, which passes events to the worker.
+async fn new_callback(
+    config: &Config,
+    client: &Client,
+) -> SimpleResult<(http_server::Callback, Channel)> {
+    let http = &config.http;
+    let confirmation = match &http.callback_confirmation {
+        Some(c) => c.clone(),
+        None => client.callback_confirmation_code().await?,
+    };
+    let (tx, rx) = channel(100);
+    let callback = http_server::Callback {
+        path: http.callback_path.clone(),
+        group_id: config.vk.group_id,
+        confirmation,
+        secret: http
+            .callback_secret
+            .clone()
+            .ok_or_else(|| Error::new("no http.callback_secret"))?,
+        events: tx,
+    };
+    Ok((callback, Channel::new(rx)))
+}
+
+fn start_http_server(
+    config: &Config,
+    callback: Option<http_server::Callback>,
+    ct: &CancellationToken,
+) -> SimpleResult<()> {
+    let addr = match (config.http.addr, &callback) {
+        (Some(addr), _) => addr,
+        (None, Some(_)) => ([0, 0, 0, 0], 8080).into(),
+        (None, None) => return Ok(()),
+    };
+    let (addr, _) = http_server::serve(addr, callback, ct.clone())?;
+    info!("listen on {}", addr);
+    Ok(())
+}
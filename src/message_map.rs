@@ -0,0 +1,125 @@
+use crate::recent::Recent;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+
+/// Board comment as `(topic_id, id)`.
+pub type PostKey = (i64, i64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SentMessage {
+    pub peer_id: i64,
+    pub conversation_message_id: i64,
+}
+
+/// Remembers which chat messages were sent for the latest board comments,
+/// so edits and deletions can be applied to them.
+#[derive(Debug)]
+pub struct MessageMap {
+    order: Recent<PostKey>,
+    messages: HashMap<PostKey, Vec<SentMessage>>,
+}
+
+impl MessageMap {
+    pub fn new(capacity: usize) -> MessageMap {
+        MessageMap {
+            order: Recent::new(capacity),
+            messages: HashMap::new(),
+        }
+    }
+
+    pub fn limit(&mut self, capacity: usize) {
+        let evicted = self.order.limit(capacity);
+        self.forget(evicted);
+    }
+
+    pub fn insert(&mut self, key: PostKey, message: SentMessage) {
+        self.messages.entry(key).or_default().push(message);
+        let evicted = self.order.insert(key);
+        self.forget(evicted);
+    }
+
+    pub fn get(&self, key: &PostKey) -> &[SentMessage] {
+        self.messages.get(key).map(|l| l.as_slice()).unwrap_or(&[])
+    }
+
+    fn forget(&mut self, keys: Vec<PostKey>) {
+        for key in keys {
+            self.messages.remove(&key);
+        }
+    }
+}
+
+impl Default for MessageMap {
+    fn default() -> MessageMap {
+        MessageMap::new(usize::MAX)
+    }
+}
+
+/// Stored as a list of comments with their messages, oldest first.
+impl Serialize for MessageMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.order.iter().map(|key| (key, self.get(key))))
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MessageMap, D::Error> {
+        let list: Vec<(PostKey, Vec<SentMessage>)> = Vec::deserialize(deserializer)?;
+        let mut map = MessageMap::default();
+        for (key, messages) in list {
+            for m in messages {
+                map.insert(key, m);
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sent(peer_id: i64, conversation_message_id: i64) -> SentMessage {
+        SentMessage {
+            peer_id,
+            conversation_message_id,
+        }
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut map = MessageMap::new(10);
+        map.insert((1, 2), sent(100, 5));
+        map.insert((1, 2), sent(200, 7));
+        assert_eq!(&[sent(100, 5), sent(200, 7)], map.get(&(1, 2)));
+        assert!(map.get(&(1, 3)).is_empty());
+    }
+
+    #[test]
+    fn forget_oldest() {
+        let mut map = MessageMap::new(2);
+        map.insert((1, 1), sent(100, 1));
+        map.insert((1, 2), sent(100, 2));
+        map.insert((1, 2), sent(200, 2));
+        map.insert((1, 3), sent(100, 3));
+        assert!(map.get(&(1, 1)).is_empty());
+        assert_eq!(2, map.get(&(1, 2)).len());
+        assert_eq!(1, map.get(&(1, 3)).len());
+    }
+
+    #[test]
+    fn serialize_in_order() {
+        let mut map = MessageMap::new(10);
+        map.insert((1, 2), sent(100, 5));
+        map.insert((1, 1), sent(100, 6));
+        let text = serde_json::to_string(&map).unwrap();
+        assert_eq!(
+            r#"[[[1,2],[{"peer_id":100,"conversation_message_id":5}]],[[1,1],[{"peer_id":100,"conversation_message_id":6}]]]"#,
+            text
+        );
+        let mut map: MessageMap = serde_json::from_str(&text).unwrap();
+        map.limit(1);
+        assert!(map.get(&(1, 2)).is_empty());
+        assert_eq!(&[sent(100, 6)], map.get(&(1, 1)));
+    }
+}
@@ -0,0 +1,134 @@
+use crate::clock;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+/// Counters of the bot, shown on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    /// Events by type, like `board_post_new`.
+    pub events: IntCounterVec,
+    /// Messages sent by chat.
+    pub sent: IntCounterVec,
+    /// Failed attempts to send a message by chat.
+    pub failed: IntCounterVec,
+    /// Latency of VK API requests by method.
+    pub api_latency: HistogramVec,
+    /// Long poll refreshes, `key` or `all`.
+    pub refreshes: IntCounterVec,
+    /// Unix time of the last successful poll.
+    last_poll: IntGauge,
+    since_last_poll: IntGauge,
+    pub circuit_open: IntGauge,
+    pub queued: IntGauge,
+}
+
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry =
+            Registry::new_custom(Some("vk_bot".to_string()), None).expect("can't create registry");
+        let counter = |name: &str, help: &str, label: &str| {
+            let c = IntCounterVec::new(Opts::new(name, help), &[label]).expect("bad counter");
+            registry
+                .register(Box::new(c.clone()))
+                .expect("can't register");
+            c
+        };
+        let gauge = |name: &str, help: &str| {
+            let g = IntGauge::new(name, help).expect("bad gauge");
+            registry
+                .register(Box::new(g.clone()))
+                .expect("can't register");
+            g
+        };
+        let events = counter("events_total", "Events received by type", "type");
+        let sent = counter("messages_sent_total", "Messages sent by chat", "peer_id");
+        let failed = counter(
+            "messages_failed_total",
+            "Failed attempts to send a message by chat",
+            "peer_id",
+        );
+        let refreshes = counter(
+            "long_poll_refreshes_total",
+            "Long poll server refreshes by kind",
+            "kind",
+        );
+        let last_poll = gauge(
+            "last_poll_timestamp_seconds",
+            "Unix time of the last successful poll",
+        );
+        let since_last_poll = gauge(
+            "seconds_since_last_poll",
+            "Seconds since the last successful poll",
+        );
+        let circuit_open = gauge("circuit_open", "1 while the circuit breaker is open");
+        let queued = gauge("messages_queued", "Messages waiting to be sent");
+        let api_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "api_request_seconds",
+                "Latency of VK API requests by method",
+            ),
+            &["method"],
+        )
+        .expect("bad histogram");
+        registry
+            .register(Box::new(api_latency.clone()))
+            .expect("can't register");
+        Metrics {
+            registry,
+            events,
+            sent,
+            failed,
+            api_latency,
+            refreshes,
+            last_poll,
+            since_last_poll,
+            circuit_open,
+            queued,
+        }
+    }
+
+    pub fn polled(&self) {
+        self.last_poll.set(unix_now());
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let last_poll = self.last_poll.get();
+        if last_poll > 0 {
+            self.since_last_poll.set(unix_now() - last_poll);
+        }
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            return format!("# can't encode metrics: {}\n", e);
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+fn unix_now() -> i64 {
+    clock::now() as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let m = Metrics::new();
+        m.events.with_label_values(&["board_post_new"]).inc();
+        m.api_latency.with_label_values(&["execute"]).observe(0.25);
+        m.polled();
+        let text = m.render();
+        assert!(text.contains(r#"vk_bot_events_total{type="board_post_new"} 1"#));
+        assert!(text.contains(r#"vk_bot_api_request_seconds_count{method="execute"} 1"#));
+        assert!(text.contains("vk_bot_seconds_since_last_poll 0"));
+    }
+}
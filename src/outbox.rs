@@ -0,0 +1,230 @@
+use crate::client::Message;
+use crate::dedup::EventKey;
+use crate::error::*;
+use crate::message_map::PostKey;
+use log::error;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+/// A message waiting to be sent, with the post it repeats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outgoing {
+    pub message: Message,
+    pub event: Option<EventKey>,
+    pub post: Option<PostKey>,
+    /// Failed sends so far.
+    #[serde(default)]
+    pub attempts: u32,
+    /// Unix time of the next try.
+    #[serde(default)]
+    pub next_try: u64,
+    /// Id of the event in the logs.
+    #[serde(default)]
+    pub correlation_id: String,
+}
+
+impl Outgoing {
+    pub fn new(
+        mut message: Message,
+        event: Option<EventKey>,
+        post: Option<PostKey>,
+        correlation_id: String,
+    ) -> Outgoing {
+        message.random_id = random::<u32>().into();
+        Outgoing {
+            message,
+            event,
+            post,
+            attempts: 0,
+            next_try: 0,
+            correlation_id,
+        }
+    }
+}
+
+/// Messages waiting to be sent, kept in the state until they are sent or
+/// given up. Every chat gets its messages in order: a message waits while
+/// an earlier one to the same chat is being retried.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Outbox {
+    queue: VecDeque<Outgoing>,
+}
+
+impl Outbox {
+    pub fn push(&mut self, outgoing: Outgoing) {
+        self.queue.push_back(outgoing)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Whether a message for the post is waiting to be sent.
+    pub fn contains(&self, event: &EventKey) -> bool {
+        self.queue.iter().any(|o| o.event.as_ref() == Some(event))
+    }
+
+    /// The first message of every chat, with its position.
+    fn heads(&self) -> impl Iterator<Item = (usize, &Outgoing)> {
+        let mut peers = HashSet::new();
+        self.queue
+            .iter()
+            .enumerate()
+            .filter(move |(_, o)| peers.insert(o.message.peer_id))
+    }
+
+    /// Positions of the first message of every chat, if it's time to try it.
+    pub fn due(&self, now: u64) -> Vec<usize> {
+        self.heads()
+            .filter(|(_, o)| o.next_try <= now)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Unix time when the next message should be tried.
+    pub fn next_try(&self) -> Option<u64> {
+        self.heads().map(|(_, o)| o.next_try).min()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Outgoing> {
+        self.queue.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Outgoing> {
+        self.queue.get_mut(index)
+    }
+
+    /// Removes messages at the positions, which must be sorted.
+    pub fn remove(&mut self, indexes: &[usize]) -> Vec<Outgoing> {
+        indexes
+            .iter()
+            .rev()
+            .filter_map(|i| self.queue.remove(*i))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    time: u64,
+    error: String,
+    #[serde(flatten)]
+    outgoing: &'a Outgoing,
+}
+
+/// Appends a message that couldn't be sent to the dead letter file, one
+/// JSON per line, or only logs it when there is no file.
+pub async fn dead_letter(file_name: Option<&str>, outgoing: &Outgoing, e: &Error, now: u64) {
+    error!(
+        correlation_id = outgoing.correlation_id.as_str(),
+        peer_id = outgoing.message.peer_id;
+        "give up sending to {} after {} attempts: {}",
+        outgoing.message.peer_id,
+        outgoing.attempts + 1,
+        e
+    );
+    let file_name = match file_name {
+        Some(f) => f,
+        None => return,
+    };
+    if let Err(e) = append(file_name, outgoing, e, now).await {
+        error!("{}, message: {:?}", e, outgoing.message);
+    }
+}
+
+async fn append(file_name: &str, outgoing: &Outgoing, e: &Error, now: u64) -> SimpleResult<()> {
+    let letter = DeadLetter {
+        time: now,
+        error: e.to_string(),
+        outgoing,
+    };
+    let mut line = serde_json::to_vec(&letter).map_err(|e| e.wrap("can't serialize message"))?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_name)
+        .await
+        .wrap_err("can't open dead letter file")?;
+    file.write_all(&line)
+        .await
+        .wrap_err("can't write dead letter file")?;
+    file.sync_all()
+        .await
+        .wrap_err("can't sync dead letter file")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::long_poll_client::EventKind;
+
+    fn outgoing(peer_id: i64, next_try: u64) -> Outgoing {
+        let mut o = Outgoing::new(
+            Message {
+                peer_id,
+                text: Some("text".to_owned()),
+                attachment: None,
+                random_id: 0,
+            },
+            None,
+            None,
+            "c1".to_owned(),
+        );
+        o.next_try = next_try;
+        o.message.random_id = 1;
+        o
+    }
+
+    #[test]
+    fn in_order_per_chat() {
+        let mut outbox = Outbox::default();
+        outbox.push(outgoing(1, 100));
+        outbox.push(outgoing(2, 0));
+        outbox.push(outgoing(1, 0));
+        outbox.push(outgoing(2, 0));
+        outbox.push(outgoing(3, 0));
+        assert_eq!(vec![1, 4], outbox.due(50));
+        assert_eq!(vec![0, 1, 4], outbox.due(100));
+        assert_eq!(Some(0), outbox.next_try());
+        assert!(!outbox.contains(&(EventKind::WallPost, 0, 1)));
+        outbox.get_mut(4).unwrap().event = Some((EventKind::WallPost, 0, 1));
+        assert!(outbox.contains(&(EventKind::WallPost, 0, 1)));
+
+        let mut waiting = Outbox::default();
+        waiting.push(outgoing(1, 100));
+        waiting.push(outgoing(1, 0));
+        assert_eq!(Some(100), waiting.next_try());
+
+        let removed = outbox.remove(&[0, 1, 4]);
+        let peers: Vec<i64> = removed.iter().map(|o| o.message.peer_id).collect();
+        assert_eq!(vec![1, 2, 3], peers);
+        assert_eq!(vec![0, 1], outbox.due(0));
+    }
+
+    #[tokio::test]
+    async fn write_dead_letter() {
+        let dir = std::env::temp_dir().join(format!("vk-bot-outbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_name = dir.join("dead.jsonl").to_str().unwrap().to_owned();
+        let e = Error::new("bad");
+        dead_letter(Some(&file_name), &outgoing(1, 0), &e, 10).await;
+        dead_letter(Some(&file_name), &outgoing(2, 0), &e, 20).await;
+
+        let text = std::fs::read_to_string(&file_name).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(2, lines.len());
+        assert_eq!(
+            r#"{"time":10,"error":"bad","message":{"peer_id":1,"text":"text","attachment":null,"random_id":1},"event":null,"post":null,"attempts":0,"next_try":0,"correlation_id":"c1"}"#,
+            lines[0]
+        );
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
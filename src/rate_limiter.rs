@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+/// Token bucket shared by all API calls of a client. Callers wait in the
+/// order they came, because the bucket lock is held while sleeping.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> RateLimiter {
+        RateLimiter {
+            bucket: Mutex::new(Bucket::new(per_second, Instant::now())),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let mut bucket = self.bucket.lock().await;
+        while let Some(wait) = bucket.take(Instant::now()) {
+            sleep(wait).await;
+        }
+    }
+}
+
+impl Bucket {
+    fn new(per_second: u32, now: Instant) -> Bucket {
+        let per_second = f64::from(per_second.max(1));
+        Bucket {
+            capacity: per_second,
+            per_second,
+            tokens: per_second,
+            updated: now,
+        }
+    }
+
+    /// Takes a token, or returns how long to wait for the next one.
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn take_burst_then_wait() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2, now);
+        assert_eq!(None, bucket.take(now));
+        assert_eq!(None, bucket.take(now));
+        assert_eq!(Some(Duration::from_millis(500)), bucket.take(now));
+        assert_eq!(None, bucket.take(now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn refill_up_to_capacity() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2, now);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(None, bucket.take(later));
+        assert_eq!(None, bucket.take(later));
+        assert!(bucket.take(later).is_some());
+    }
+}
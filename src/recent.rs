@@ -0,0 +1,113 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Keys in the order they were added. The oldest keys are forgotten when
+/// `capacity` is exceeded.
+#[derive(Debug)]
+pub struct Recent<K> {
+    capacity: usize,
+    order: VecDeque<K>,
+    keys: HashSet<K>,
+}
+
+impl<K: Clone + Eq + Hash> Recent<K> {
+    pub fn new(capacity: usize) -> Recent<K> {
+        Recent {
+            capacity,
+            order: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    /// Returns the keys forgotten to fit the new capacity.
+    pub fn limit(&mut self, capacity: usize) -> Vec<K> {
+        self.capacity = capacity;
+        self.evict()
+    }
+
+    /// Adds the key if it's new and returns the keys forgotten to fit it.
+    pub fn insert(&mut self, key: K) -> Vec<K> {
+        if self.keys.insert(key.clone()) {
+            self.order.push_back(key);
+            self.evict()
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Keys from the oldest.
+    pub fn iter(&self) -> impl Iterator<Item = &K> {
+        self.order.iter()
+    }
+
+    fn evict(&mut self) -> Vec<K> {
+        let mut evicted = vec![];
+        while self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.keys.remove(&old);
+                evicted.push(old);
+            }
+        }
+        evicted
+    }
+}
+
+impl<K: Clone + Eq + Hash> Default for Recent<K> {
+    fn default() -> Recent<K> {
+        Recent::new(usize::MAX)
+    }
+}
+
+/// Stored as a list of keys, oldest first.
+impl<K: Serialize> Serialize for Recent<K> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.order.iter())
+    }
+}
+
+impl<'de, K: Clone + Eq + Hash + Deserialize<'de>> Deserialize<'de> for Recent<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Recent<K>, D::Error> {
+        let list: Vec<K> = Vec::deserialize(deserializer)?;
+        let mut recent = Recent::default();
+        for key in list {
+            recent.insert(key);
+        }
+        Ok(recent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forget_oldest() {
+        let mut recent = Recent::new(2);
+        assert!(recent.insert(1).is_empty());
+        assert!(recent.insert(2).is_empty());
+        assert!(recent.insert(2).is_empty());
+        assert!(recent.contains(&1));
+        assert_eq!(vec![1], recent.insert(3));
+        assert!(!recent.contains(&1));
+        assert!(recent.contains(&2));
+        assert_eq!(vec![2], recent.limit(1));
+    }
+
+    #[test]
+    fn serialize_in_order() {
+        let mut recent = Recent::new(10);
+        recent.insert((1, 2));
+        recent.insert((0, 1));
+        let text = serde_json::to_string(&recent).unwrap();
+        assert_eq!("[[1,2],[0,1]]", text);
+        let mut recent: Recent<(i64, i64)> = serde_json::from_str(&text).unwrap();
+        recent.limit(1);
+        assert!(!recent.contains(&(1, 2)));
+        assert!(recent.contains(&(0, 1)));
+    }
+}
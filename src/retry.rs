@@ -0,0 +1,264 @@
+use crate::metrics::metrics;
+use log::{info, warn};
+use rand::random;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// What the worker was doing when an error happened. Each operation backs
+/// off on its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    /// Waiting for events.
+    Poll,
+    /// Sending or editing messages.
+    Send,
+    /// Resolving authors and topics.
+    Lookup,
+}
+
+/// Exponential backoff: the delay doubles after every failure up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay is taken at random from the upper half, so that clients
+    /// which failed together don't retry together. `jitter` is in `[0, 1)`.
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let base = self
+            .initial
+            .checked_mul(1 << attempt.min(31))
+            .unwrap_or(self.max)
+            .min(self.max);
+        base / 2 + base.mul_f64(jitter / 2.0)
+    }
+}
+
+#[derive(Debug)]
+struct Backoff {
+    policy: RetryPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    fn new(policy: RetryPolicy) -> Backoff {
+        Backoff { policy, attempt: 0 }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.policy.delay(self.attempt, random());
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed,
+    /// Requests wait for the cool down after too many failures in a row.
+    Open,
+    /// The cool down is over, the next request decides the state.
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Stops calling VK for `cool_down` after `threshold` failures in a row.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cool_down: Duration,
+    failures: u32,
+    opened: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cool_down: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cool_down,
+            failures: 0,
+            opened: None,
+        }
+    }
+
+    pub fn state(&self, now: Instant) -> BreakerState {
+        match self.opened {
+            None => BreakerState::Closed,
+            Some(opened) if now.duration_since(opened) < self.cool_down => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn success(&mut self) {
+        if self.opened.take().is_some() {
+            info!("circuit breaker closed");
+            metrics().circuit_open.set(0);
+        }
+        self.failures = 0;
+    }
+
+    pub fn failure(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        let open = match self.state(now) {
+            BreakerState::Closed => self.failures >= self.threshold,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => true,
+        };
+        if open {
+            warn!(
+                "circuit breaker open for {:?} after {} failures",
+                self.cool_down, self.failures
+            );
+            self.opened = Some(now);
+            metrics().circuit_open.set(1);
+        }
+    }
+
+    /// Time left until VK may be called again.
+    pub fn wait(&self, now: Instant) -> Duration {
+        match self.opened {
+            Some(opened) => self.cool_down.saturating_sub(now.duration_since(opened)),
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+/// Backoff for every operation, with a circuit breaker for the calls of the
+/// VK API. Polls wait on another host, so they neither open nor close it.
+#[derive(Debug)]
+pub struct Retry {
+    poll: Backoff,
+    send: Backoff,
+    lookup: Backoff,
+    pub breaker: CircuitBreaker,
+}
+
+impl Retry {
+    pub fn new(
+        poll: RetryPolicy,
+        send: RetryPolicy,
+        lookup: RetryPolicy,
+        breaker: CircuitBreaker,
+    ) -> Retry {
+        Retry {
+            poll: Backoff::new(poll),
+            send: Backoff::new(send),
+            lookup: Backoff::new(lookup),
+            breaker,
+        }
+    }
+
+    fn backoff(&self, operation: Operation) -> &Backoff {
+        match operation {
+            Operation::Poll => &self.poll,
+            Operation::Send => &self.send,
+            Operation::Lookup => &self.lookup,
+        }
+    }
+
+    fn backoff_mut(&mut self, operation: Operation) -> &mut Backoff {
+        match operation {
+            Operation::Poll => &mut self.poll,
+            Operation::Send => &mut self.send,
+            Operation::Lookup => &mut self.lookup,
+        }
+    }
+
+    /// Delay before the next try after `attempt` failures, for retries
+    /// scheduled by the caller.
+    pub fn delay(&self, operation: Operation, attempt: u32) -> Duration {
+        self.backoff(operation).policy.delay(attempt, random())
+    }
+
+    pub fn success(&mut self, operation: Operation) {
+        self.backoff_mut(operation).attempt = 0;
+        if operation != Operation::Poll {
+            self.breaker.success();
+        }
+    }
+
+    /// Records the failure and returns how long to wait before retrying.
+    pub fn failure(&mut self, operation: Operation) -> Duration {
+        let delay = self.backoff_mut(operation).next_delay();
+        if operation == Operation::Poll {
+            return delay;
+        }
+        let now = Instant::now();
+        self.breaker.failure(now);
+        delay.max(self.breaker.wait(now))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn exponential_delay() {
+        let policy = RetryPolicy {
+            initial: secs(2),
+            max: secs(60),
+        };
+        assert_eq!(secs(1), policy.delay(0, 0.0));
+        assert_eq!(secs(4), policy.delay(2, 0.0));
+        assert_eq!(Duration::from_millis(7500), policy.delay(2, 0.875));
+        assert_eq!(secs(30), policy.delay(5, 0.0));
+        assert_eq!(secs(30), policy.delay(100, 0.0));
+    }
+
+    #[test]
+    fn breaker_states() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, secs(10));
+        breaker.failure(start);
+        assert_eq!(BreakerState::Closed, breaker.state(start));
+        breaker.failure(start);
+        assert_eq!(BreakerState::Open, breaker.state(start));
+        assert_eq!(secs(6), breaker.wait(start + secs(4)));
+
+        let later = start + secs(10);
+        assert_eq!(BreakerState::HalfOpen, breaker.state(later));
+        breaker.failure(later);
+        assert_eq!(BreakerState::Open, breaker.state(later));
+
+        breaker.success();
+        assert_eq!(BreakerState::Closed, breaker.state(later));
+        assert_eq!(secs(0), breaker.wait(later));
+    }
+
+    #[test]
+    fn polls_keep_breaker() {
+        let policy = RetryPolicy {
+            initial: secs(1),
+            max: secs(1),
+        };
+        let mut retry = Retry::new(policy, policy, policy, CircuitBreaker::new(2, secs(10)));
+        retry.failure(Operation::Send);
+        retry.success(Operation::Poll);
+        retry.failure(Operation::Poll);
+        assert_eq!(BreakerState::Closed, retry.breaker.state(Instant::now()));
+        let delay = retry.failure(Operation::Send);
+        assert_eq!(BreakerState::Open, retry.breaker.state(Instant::now()));
+        assert!(delay > secs(5));
+        retry.success(Operation::Poll);
+        assert_eq!(BreakerState::Open, retry.breaker.state(Instant::now()));
+        retry.success(Operation::Send);
+        assert_eq!(BreakerState::Closed, retry.breaker.state(Instant::now()));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::error::*;
+use crate::filter::Filter;
+use crate::long_poll_client::{Event, EventKind};
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+    /// Filters of board posts by chat.
+    #[serde(default, deserialize_with = "by_chat")]
+    filters: HashMap<i64, Filter>,
+}
+
+/// `[routing]` of the config: a routes file, the routes inline or a single
+/// chat, in this order, with filters for the latter two.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingSection {
+    pub chat: Option<i64>,
+    pub file: Option<String>,
+    routes: Option<Vec<Route>>,
+    #[serde(default, deserialize_with = "by_chat")]
+    filters: HashMap<i64, Filter>,
+}
+
+/// One entry of the routing table. Empty `events`, `topics` or `keywords`
+/// mean "any", so `{"peer_ids": [2000000001]}` forwards everything.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    #[serde(default)]
+    events: Vec<EventKind>,
+    #[serde(default)]
+    topics: Vec<i64>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    peer_ids: Vec<i64>,
+}
+
+pub fn from_file(file_name: &str) -> SimpleResult<RoutingTable> {
+    let text = std::fs::read_to_string(file_name).wrap_err("can't read routes")?;
+    parse(&text)
+}
+
+fn parse(text: &str) -> SimpleResult<RoutingTable> {
+    let table: RoutingTable =
+        serde_json::from_str(text).map_err(|e| e.wrap("can't parse routes"))?;
+    table.check()
+}
+
+/// Settings by chat id. Keys of TOML tables are always strings, so the ids
+/// are parsed from them, like serde_json does.
+pub fn by_chat<'de, D, V>(d: D) -> Result<HashMap<i64, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(d)?
+        .into_iter()
+        .map(|(k, v)| match k.parse() {
+            Ok(id) => Ok((id, v)),
+            Err(_) => Err(de::Error::custom(format!("bad chat id {}", k))),
+        })
+        .collect()
+}
+
+impl RoutingSection {
+    /// `None` when neither a file, nor routes, nor a chat is set.
+    pub fn load(self) -> Option<SimpleResult<RoutingTable>> {
+        let table = match (self.file, self.routes, self.chat) {
+            (Some(file_name), _, _) => return Some(from_file(&file_name)),
+            (None, Some(routes), _) => RoutingTable {
+                routes,
+                filters: self.filters,
+            },
+            (None, None, Some(chat)) => RoutingTable {
+                filters: self.filters,
+                ..RoutingTable::single(chat)
+            },
+            (None, None, None) => return None,
+        };
+        Some(table.check())
+    }
+}
+
+impl RoutingTable {
+    fn check(self) -> SimpleResult<RoutingTable> {
+        if let Some(r) = self.routes.iter().find(|r| r.peer_ids.is_empty()) {
+            return Err(Error::new(format!("route without peer_ids: {:?}", r)));
+        }
+        Ok(self)
+    }
+
+    pub fn single(peer_id: i64) -> RoutingTable {
+        RoutingTable {
+            routes: vec![Route {
+                events: vec![],
+                topics: vec![],
+                keywords: vec![],
+                peer_ids: vec![peer_id],
+            }],
+            filters: HashMap::new(),
+        }
+    }
+
+    /// Returns every chat the event should be sent to, without duplicates,
+    /// in the order they first appear in the table, except chats whose
+    /// filter rejects it.
+    pub fn destinations(&self, event: &Event) -> Vec<i64> {
+        let mut result: Vec<i64> = vec![];
+        for route in self.routes.iter().filter(|r| r.matches(event)) {
+            for peer_id in &route.peer_ids {
+                if !result.contains(peer_id) {
+                    result.push(*peer_id);
+                }
+            }
+        }
+        result.retain(|p| self.filters.get(p).is_none_or(|f| f.allows(event)));
+        result
+    }
+}
+
+impl Route {
+    fn matches(&self, event: &Event) -> bool {
+        if !self.events.is_empty() && !self.events.contains(&event.kind()) {
+            return false;
+        }
+        let text = match event {
+            Event::BoardPost(c) | Event::BoardPostEdit(c) | Event::BoardPostRestore(c) => {
+                c.text.as_str()
+            }
+            Event::BoardPostDelete { .. } => "",
+            Event::WallPost { text, .. } | Event::Message { text, .. } => text.as_str(),
+        };
+        let topic_id = event.topic_id();
+        if !self.topics.is_empty() {
+            match topic_id {
+                Some(id) if self.topics.contains(&id) => {}
+                _ => return false,
+            }
+        }
+        if !self.keywords.is_empty() {
+            let text = text.to_lowercase();
+            return self
+                .keywords
+                .iter()
+                .any(|k| text.contains(&k.to_lowercase()));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::long_poll_client::BoardComment;
+
+    fn board(topic_id: i64, text: &str) -> Event {
+        Event::BoardPost(BoardComment {
+            from_id: 1,
+            text: text.to_owned(),
+            topic_id,
+            id: 1,
+            date: 0,
+            attachments: vec![],
+        })
+    }
+
+    fn wall(text: &str) -> Event {
+        Event::WallPost {
+            id: 1,
+            text: text.to_owned(),
+            date: 0,
+        }
+    }
+
+    #[test]
+    fn parse_table() {
+        let source = r#"
+{
+   "routes":[
+      {
+         "events":["board_post"],
+         "topics":[10, 11],
+         "keywords":["release"],
+         "peer_ids":[2000000001]
+      },
+      {
+         "peer_ids":[2000000002]
+      }
+   ]
+}"#;
+        let table = parse(source).unwrap();
+        assert_eq!(
+            RoutingTable {
+                routes: vec!(
+                    Route {
+                        events: vec!(EventKind::BoardPost),
+                        topics: vec!(10, 11),
+                        keywords: vec!("release".to_owned()),
+                        peer_ids: vec!(2000000001),
+                    },
+                    Route {
+                        events: vec!(),
+                        topics: vec!(),
+                        keywords: vec!(),
+                        peer_ids: vec!(2000000002),
+                    }
+                ),
+                filters: HashMap::new(),
+            },
+            table
+        );
+    }
+
+    #[test]
+    fn parse_without_peers() {
+        assert!(parse(r#"{"routes":[{"peer_ids":[]}]}"#).is_err());
+    }
+
+    #[test]
+    fn parse_unknown_fields() {
+        let e = parse(r#"{"routes":[{"topic":[10], "peer_ids":[1]}]}"#).unwrap_err();
+        assert!(e.to_string().contains("unknown field `topic`"), "{}", e);
+        assert!(parse(r#"{"routes":[], "filter":{}}"#).is_err());
+    }
+
+    #[test]
+    fn route_events() {
+        let table = parse(
+            r#"
+{
+   "routes":[
+      {"events":["board_post"], "topics":[10], "peer_ids":[1]},
+      {"events":["board_post"], "keywords":["Release"], "peer_ids":[2, 1]},
+      {"events":["wall_post"], "peer_ids":[3]}
+   ]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(vec!(1), table.destinations(&board(10, "text")));
+        assert_eq!(vec!(1, 2), table.destinations(&board(10, "new release")));
+        assert_eq!(vec!(2, 1), table.destinations(&board(11, "RELEASE")));
+        assert!(table.destinations(&board(11, "text")).is_empty());
+        assert_eq!(vec!(3), table.destinations(&wall("release")));
+    }
+
+    #[test]
+    fn filter_by_chat() {
+        let table = parse(
+            r#"
+{
+   "routes":[{"peer_ids":[1, 2]}],
+   "filters":{"2":{"exclude_keywords":["draft"]}}
+}"#,
+        )
+        .unwrap();
+        assert_eq!(vec!(1, 2), table.destinations(&board(10, "text")));
+        assert_eq!(vec!(1), table.destinations(&board(10, "Draft")));
+    }
+
+    #[test]
+    fn route_single() {
+        let table = RoutingTable::single(5);
+        assert_eq!(vec!(5), table.destinations(&board(10, "text")));
+        assert_eq!(vec!(5), table.destinations(&wall("")));
+    }
+}
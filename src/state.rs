@@ -0,0 +1,283 @@
+use crate::author_cache::AuthorCache;
+use crate::catch_up::BoardCursor;
+use crate::client::ServerConfig;
+use crate::dedup::Seen;
+use crate::error::*;
+use crate::message_map::MessageMap;
+use crate::outbox::Outbox;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+/// Version of the state file format. Files without a version hold only
+/// `ServerConfig` in the first line of a 1 KB file.
+pub const VERSION: u32 = 1;
+
+/// Everything the bot keeps between restarts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    version: u32,
+    /// Long poll server, missing until the first poll and in callback mode.
+    #[serde(default)]
+    pub server: Option<ServerConfig>,
+    #[serde(default)]
+    pub authors: AuthorCache,
+    #[serde(default)]
+    pub sent: MessageMap,
+    /// Latest board comments, to catch up after downtime.
+    #[serde(default)]
+    pub board: BoardCursor,
+    /// Id of the latest forwarded wall post, to catch up after downtime.
+    #[serde(default)]
+    pub last_wall_post: i64,
+    /// Latest forwarded posts, to skip them when they come again.
+    #[serde(default)]
+    pub seen: Seen,
+    /// Messages waiting to be sent.
+    #[serde(default)]
+    pub outbox: Outbox,
+    /// Topics muted with `/mute`, by chat.
+    #[serde(default)]
+    pub muted: HashMap<i64, HashSet<i64>>,
+}
+
+pub struct StateStore {
+    file_name: String,
+}
+
+impl State {
+    pub fn new(server: Option<ServerConfig>) -> State {
+        State {
+            version: VERSION,
+            server,
+            authors: AuthorCache::default(),
+            sent: MessageMap::default(),
+            board: BoardCursor::default(),
+            last_wall_post: 0,
+            seen: Seen::default(),
+            outbox: Outbox::default(),
+            muted: HashMap::new(),
+        }
+    }
+}
+
+pub async fn write(store: &Option<StateStore>, state: &State) {
+    if let Some(s) = store {
+        if let Err(e) = s.write(state).await {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Reads the state from the file, migrating older formats.
+pub async fn with_file(file_name: &str) -> SimpleResult<(StateStore, State)> {
+    let store = StateStore {
+        file_name: file_name.to_string(),
+    };
+    let mut state = match store.read().await? {
+        Some(s) => s,
+        None => State::new(None),
+    };
+    let authors_file = authors_file_name(file_name);
+    let authors = read_authors(&authors_file).await;
+    let migrated = authors.is_some();
+    if let Some(authors) = authors {
+        info!("migrate {}", authors_file);
+        state.authors = authors;
+    }
+    store.write(&state).await?;
+    if migrated {
+        tokio::fs::remove_file(&authors_file)
+            .await
+            .wrap_err("can't remove authors file")?;
+    }
+    Ok((store, state))
+}
+
+impl StateStore {
+    /// A file which can't be parsed is kept as `<file>.corrupt`, so it's not
+    /// lost when the new state is written.
+    async fn read(&self) -> SimpleResult<Option<State>> {
+        let bytes = match tokio::fs::read(&self.file_name).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.wrap("can't read state")),
+        };
+        let state = parse(&bytes)?;
+        if state.is_none() && !bytes.is_empty() {
+            let corrupt = format!("{}.corrupt", self.file_name);
+            tokio::fs::rename(&self.file_name, &corrupt)
+                .await
+                .wrap_err("can't keep corrupt state")?;
+            error!("state is moved to {}, starting from scratch", corrupt);
+        }
+        Ok(state)
+    }
+
+    /// Writes to a temporary file and renames it, so a crash leaves
+    /// either the old or the new state.
+    pub async fn write(&self, state: &State) -> SimpleResult<()> {
+        let text = serde_json::to_vec(state).map_err(|e| e.wrap("can't serialize state"))?;
+        let tmp = format!("{}.tmp", self.file_name);
+        let mut file = File::create(&tmp).await.wrap_err("can't create state")?;
+        file.write_all(&text).await.wrap_err("can't write state")?;
+        file.sync_all().await.wrap_err("can't sync state")?;
+        tokio::fs::rename(&tmp, &self.file_name)
+            .await
+            .wrap_err("can't rename state")?;
+        sync_dir(&self.file_name).await;
+        Ok(())
+    }
+}
+
+/// Makes the rename durable. Not every platform can sync a directory.
+async fn sync_dir(file_name: &str) {
+    let dir = match Path::new(file_name).parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return,
+    };
+    let r = match File::open(dir).await {
+        Ok(d) => d.sync_all().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = r {
+        debug!("can't sync {:?}: {}", dir, e);
+    }
+}
+
+/// Both formats keep the whole JSON in the first line. Old files are
+/// padded with zeros and may have leftovers of longer lines after it.
+fn parse(bytes: &[u8]) -> SimpleResult<Option<State>> {
+    let text = String::from_utf8_lossy(bytes);
+    let line = text.lines().next().unwrap_or_default();
+    let value: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("can't parse state: {}", e);
+            return Ok(None);
+        }
+    };
+    let version = value.get("version").and_then(|v| v.as_u64());
+    match version {
+        None => {
+            info!("migrate server config to state version {}", VERSION);
+            let server: ServerConfig =
+                serde_json::from_value(value).map_err(|e| e.wrap("can't parse server config"))?;
+            Ok(Some(State::new(Some(server))))
+        }
+        Some(v) if v > u64::from(VERSION) => Err(Error::new(format!(
+            "state version {} is newer than supported {}",
+            v, VERSION
+        ))),
+        Some(_) => {
+            let mut state: State =
+                serde_json::from_value(value).map_err(|e| e.wrap("can't parse state"))?;
+            state.version = VERSION;
+            Ok(Some(state))
+        }
+    }
+}
+
+fn authors_file_name(file_name: &str) -> String {
+    format!("{}.authors", file_name)
+}
+
+async fn read_authors(file_name: &str) -> Option<AuthorCache> {
+    let text = tokio::fs::read_to_string(file_name).await.ok()?;
+    match serde_json::from_str(&text) {
+        Ok(authors) => Some(authors),
+        Err(e) => {
+            error!("can't parse {}: {}", file_name, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message_map::SentMessage;
+
+    fn server() -> ServerConfig {
+        ServerConfig {
+            key: "key".to_owned(),
+            server: "https://lp.vk.com/wh1".to_owned(),
+            ts: "10".to_owned(),
+        }
+    }
+
+    fn temp_file(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("vk-bot-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn migrate_server_config() {
+        let mut bytes = br#"{"key":"key","server":"https://lp.vk.com/wh1","ts":"10"}
+"server":"old"}"#
+            .to_vec();
+        bytes.resize(1024, 0);
+        let state = parse(&bytes).unwrap().unwrap();
+        assert_eq!(VERSION, state.version);
+        let server = state.server.unwrap();
+        assert_eq!("10", server.ts);
+        assert_eq!("key", server.key);
+    }
+
+    #[test]
+    fn parse_broken() {
+        assert!(parse(b"").unwrap().is_none());
+        assert!(parse(&[0; 1024]).unwrap().is_none());
+        assert!(parse(br#"{"version":1000,"server":{}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn write_and_read() {
+        let store = StateStore {
+            file_name: temp_file("state.json"),
+        };
+        let mut state = State::new(Some(server()));
+        state.sent.insert(
+            (1, 2),
+            SentMessage {
+                peer_id: 100,
+                conversation_message_id: 5,
+            },
+        );
+        state.muted.entry(100).or_default().insert(1);
+        store.write(&state).await.unwrap();
+        store.write(&state).await.unwrap();
+
+        let read = store.read().await.unwrap().unwrap();
+        assert_eq!("10", read.server.unwrap().ts);
+        assert_eq!(1, read.sent.get(&(1, 2)).len());
+        assert!(read.muted[&100].contains(&1));
+        assert!(!Path::new(&format!("{}.tmp", store.file_name)).exists());
+        std::fs::remove_file(&store.file_name).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_corrupt() {
+        let file_name = temp_file("corrupt.json");
+        std::fs::write(&file_name, "{\"version\":1,").unwrap();
+        let (_, state) = with_file(&file_name).await.unwrap();
+        assert!(state.server.is_none());
+        let corrupt = format!("{}.corrupt", file_name);
+        assert_eq!(
+            "{\"version\":1,",
+            std::fs::read_to_string(&corrupt).unwrap()
+        );
+        assert!(std::fs::read_to_string(&file_name)
+            .unwrap()
+            .starts_with("{\"version\":1,\"server\":null"));
+        std::fs::remove_file(&corrupt).unwrap();
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use crate::error::*;
+use crate::long_poll_client::EventKind;
+use crate::routing;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+const DEFAULT_BOARD_POST: &str = "{author}: {text} \n {link}";
+const DEFAULT_TEXT_LENGTH: usize = 100;
+
+/// Message text with placeholders, e.g. `{author}: {text}`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Author,
+    AuthorLink,
+    Text,
+    TopicTitle,
+    Link,
+    Date,
+}
+
+/// Values for the placeholders. The text is cut to the configured length
+/// on render.
+#[derive(Debug, Default)]
+pub struct Values<'a> {
+    pub author: &'a str,
+    pub author_link: &'a str,
+    pub text: &'a str,
+    pub topic_title: &'a str,
+    pub link: &'a str,
+    /// Unix time.
+    pub date: i64,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> SimpleResult<Template> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(Error::new(format!("unclosed {{ in template {:?}", source)));
+                    }
+                    let field = match name.as_str() {
+                        "author" => Field::Author,
+                        "author_link" => Field::AuthorLink,
+                        "text" => Field::Text,
+                        "topic_title" => Field::TopicTitle,
+                        "link" => Field::Link,
+                        "date" => Field::Date,
+                        _ => {
+                            return Err(Error::new(format!(
+                                "unknown placeholder {{{}}} in template {:?}",
+                                name, source
+                            )))
+                        }
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err(Error::new(format!("unmatched }} in template {:?}", source))),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn uses(&self, field: Field) -> bool {
+        self.parts.contains(&Part::Field(field))
+    }
+
+    pub fn render(&self, values: &Values, text_length: usize) -> String {
+        let mut result = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => result.push_str(text),
+                Part::Field(Field::Author) => result.push_str(values.author),
+                Part::Field(Field::AuthorLink) => result.push_str(values.author_link),
+                Part::Field(Field::Text) => result.extend(values.text.chars().take(text_length)),
+                Part::Field(Field::TopicTitle) => result.push_str(values.topic_title),
+                Part::Field(Field::Link) => result.push_str(values.link),
+                Part::Field(Field::Date) => result.push_str(&format_date(values.date)),
+            }
+        }
+        result
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = Error;
+
+    fn try_from(source: String) -> SimpleResult<Template> {
+        Template::parse(&source)
+    }
+}
+
+/// Templates by event kind, with overrides for single chats.
+/// Wall posts are sent as an attachment only, unless a template is set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "TemplatesSection")]
+pub struct Templates {
+    default: ChatTemplates,
+    chats: HashMap<i64, ChatTemplates>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChatTemplates {
+    board_post: Option<Template>,
+    wall_post: Option<Template>,
+    text_length: Option<usize>,
+}
+
+/// `[templates]` of the config, or the templates file, with the defaults at
+/// the top level. Spelled out instead of flattened, as serde can't deny
+/// unknown fields of a flattened struct.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplatesSection {
+    /// The templates file, used instead of the templates here. Only in the
+    /// config.
+    pub file: Option<String>,
+    board_post: Option<Template>,
+    wall_post: Option<Template>,
+    text_length: Option<usize>,
+    #[serde(default, deserialize_with = "routing::by_chat")]
+    chats: HashMap<i64, ChatTemplates>,
+}
+
+impl TemplatesSection {
+    pub fn load(self) -> SimpleResult<Templates> {
+        match &self.file {
+            Some(file_name) => from_file(file_name),
+            None => Templates::try_from(self),
+        }
+    }
+}
+
+impl TryFrom<TemplatesSection> for Templates {
+    type Error = Error;
+
+    fn try_from(section: TemplatesSection) -> SimpleResult<Templates> {
+        if section.file.is_some() {
+            return Err(Error::new("a templates file can't point to another file"));
+        }
+        Ok(Templates {
+            default: ChatTemplates {
+                board_post: section.board_post,
+                wall_post: section.wall_post,
+                text_length: section.text_length,
+            },
+            chats: section.chats,
+        })
+    }
+}
+
+pub fn from_file(file_name: &str) -> SimpleResult<Templates> {
+    let text = std::fs::read_to_string(file_name).wrap_err("can't read templates")?;
+    parse(&text)
+}
+
+fn parse(text: &str) -> SimpleResult<Templates> {
+    serde_json::from_str(text).map_err(|e| e.wrap("can't parse templates"))
+}
+
+impl Templates {
+    pub fn get(&self, peer_id: i64, kind: EventKind) -> Option<Template> {
+        let chat = self.chats.get(&peer_id);
+        match kind {
+            EventKind::BoardPost => chat
+                .and_then(|c| c.board_post.as_ref())
+                .or(self.default.board_post.as_ref())
+                .cloned()
+                .or_else(|| Template::parse(DEFAULT_BOARD_POST).ok()),
+            EventKind::WallPost => chat
+                .and_then(|c| c.wall_post.as_ref())
+                .or(self.default.wall_post.as_ref())
+                .cloned(),
+            EventKind::Message => None,
+        }
+    }
+
+    pub fn text_length(&self, peer_id: i64) -> usize {
+        self.chats
+            .get(&peer_id)
+            .and_then(|c| c.text_length)
+            .or(self.default.text_length)
+            .unwrap_or(DEFAULT_TEXT_LENGTH)
+    }
+
+    /// Whether any template needs the field, to skip resolving it otherwise.
+    pub fn uses(&self, field: Field) -> bool {
+        self.chats
+            .values()
+            .chain(std::iter::once(&self.default))
+            .flat_map(|c| c.board_post.iter().chain(c.wall_post.iter()))
+            .any(|t| t.uses(field))
+    }
+}
+
+/// Formats unix time as `YYYY-MM-DD HH:MM` in UTC.
+pub fn format_date(date: i64) -> String {
+    let days = date.div_euclid(86400);
+    let seconds = date.rem_euclid(86400);
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn values() -> Values<'static> {
+        Values {
+            author: "Ivan",
+            author_link: "https://vk.com/id1",
+            text: "some long text",
+            topic_title: "News",
+            link: "https://vk.com/topic-1_2?post=3",
+            date: 1578870439,
+        }
+    }
+
+    #[test]
+    fn render_template() {
+        let t = Template::parse("{author} in {topic_title} at {date}: {text}\n{link}").unwrap();
+        assert_eq!(
+            "Ivan in News at 2020-01-12 23:07: some\nhttps://vk.com/topic-1_2?post=3",
+            t.render(&values(), 4)
+        );
+        assert!(t.uses(Field::TopicTitle));
+        assert!(!t.uses(Field::AuthorLink));
+    }
+
+    #[test]
+    fn render_braces() {
+        let t = Template::parse("{{{author}}}").unwrap();
+        assert_eq!("{Ivan}", t.render(&values(), 100));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("{unknown}").is_err());
+        assert!(Template::parse("{author").is_err());
+        assert!(Template::parse("text}").is_err());
+    }
+
+    #[test]
+    fn chat_templates() {
+        let templates = parse(
+            r#"
+{
+   "board_post":"{author}: {text}",
+   "text_length":10,
+   "chats":{
+      "2000000001":{"wall_post":"{link}", "text_length":20}
+   }
+}"#,
+        )
+        .unwrap();
+        let board = Template::parse("{author}: {text}").unwrap();
+        assert_eq!(Some(board.clone()), templates.get(1, EventKind::BoardPost));
+        assert_eq!(None, templates.get(1, EventKind::WallPost));
+        assert_eq!(Some(board), templates.get(2000000001, EventKind::BoardPost));
+        assert_eq!(
+            Some(Template::parse("{link}").unwrap()),
+            templates.get(2000000001, EventKind::WallPost)
+        );
+        assert_eq!(10, templates.text_length(1));
+        assert_eq!(20, templates.text_length(2000000001));
+        assert!(templates.uses(Field::Link));
+        assert!(!templates.uses(Field::Date));
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let e = parse(r#"{"board_pots":"{text}"}"#).unwrap_err();
+        assert!(
+            e.to_string().contains("unknown field `board_pots`"),
+            "{}",
+            e
+        );
+        assert!(parse(r#"{"chats":{"1":{"wall_pots":"{link}"}}}"#).is_err());
+        assert!(parse(r#"{"file":"other.json"}"#).is_err());
+    }
+
+    #[test]
+    fn default_templates() {
+        let templates = Templates::default();
+        assert_eq!(
+            Template::parse(DEFAULT_BOARD_POST).ok(),
+            templates.get(1, EventKind::BoardPost)
+        );
+        assert_eq!(DEFAULT_TEXT_LENGTH, templates.text_length(1));
+    }
+}
@@ -1,158 +1,922 @@
-use crate::client::{Client, ServerConfig};
+use crate::attachment;
+use crate::client::{Author, Client, Message};
+use crate::clock;
+use crate::commands::{self, Command};
 use crate::config;
+use crate::dedup;
 use crate::error::*;
-use crate::long_poll_client::{get_events, Event, Result};
-use crate::server_config::{write, ConfigProvider};
-use log::error;
-use std::time::Duration;
+use crate::event_source::EventSource;
+use crate::health::health;
+use crate::long_poll_client::Event;
+use crate::message_map::SentMessage;
+use crate::metrics::metrics;
+use crate::outbox::{dead_letter, Outgoing};
+use crate::retry::{BreakerState, Operation, Retry};
+use crate::routing::RoutingTable;
+use crate::state::{write, State, StateStore};
+use crate::template::{Field, Templates, Values};
+use log::{error, info, warn};
+use rand::random;
+use std::collections::HashMap;
+use std::mem::take;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
 use tokio::time::sleep;
 use tokio_util::sync::CancellationToken;
 
+const SENT_CAPACITY: usize = 1000;
+const SEEN_CAPACITY: usize = 1000;
+/// How often the worker beats while it waits before a retry.
+const BEAT: Duration = Duration::from_secs(1);
+
+/// Settings which can be reloaded without a restart.
+#[derive(Debug)]
+pub struct Settings {
+    pub routes: RoutingTable,
+    pub templates: Templates,
+}
+
 struct Worker {
     group_id: u64,
-    chat_id: i64,
+    routes: RoutingTable,
     client: Client,
-    config: ServerConfig,
-    config_provider: Option<ConfigProvider>,
-    last_error: bool,
+    state: State,
+    store: Option<StateStore>,
+    retry: Retry,
+    /// Sends of a message before it goes to the dead letter file.
+    outbox_attempts: u32,
+    dead_letter: Option<String>,
     cancelation: CancellationToken,
+    forwarded: u64,
+    started: Instant,
+    templates: Templates,
+    /// Topic titles by id, for templates.
+    topics: HashMap<i64, String>,
+    /// New settings after the config is reloaded.
+    reloads: Receiver<Settings>,
 }
 
-pub async fn run(
+pub async fn run<S: EventSource>(
     client: Client,
-    config: ServerConfig,
-    provider: Option<ConfigProvider>,
+    mut state: State,
+    store: Option<StateStore>,
+    settings: Settings,
+    mut source: S,
+    reloads: Receiver<Settings>,
     ct: CancellationToken,
 ) {
+    let config = config::get();
+    state
+        .authors
+        .limit(config.vk.author_cache_size, config.vk.author_cache_ttl);
+    state.sent.limit(SENT_CAPACITY);
+    state.seen.limit(SEEN_CAPACITY);
     let mut w = Worker {
-        group_id: config::group_id(),
-        chat_id: config::chat_peer_id(),
+        group_id: config.vk.group_id,
+        routes: settings.routes,
         client,
-        config,
-        config_provider: provider,
-        last_error: false,
+        state,
+        store,
+        retry: config.retry.retry(),
+        outbox_attempts: config.retry.outbox_attempts,
+        dead_letter: config.storage.dead_letter.clone(),
         cancelation: ct,
+        forwarded: 0,
+        started: Instant::now(),
+        templates: settings.templates,
+        topics: HashMap::new(),
+        reloads,
     };
 
-    w.main_loop().await
+    w.main_loop(&mut source).await
+}
+
+/// Random id which follows an event through the logs, from the poll to
+/// the messages it's repeated with.
+fn correlation_id() -> String {
+    format!("{:08x}", random::<u32>())
 }
 
 impl Worker {
-    pub async fn main_loop(&mut self) {
-        let raw_client = self.client.raw_client();
+    pub async fn main_loop<S: EventSource>(&mut self, source: &mut S) {
         let ct = self.cancelation.clone();
         loop {
-            tokio::select! {
+            health().beat();
+            let retry_in = self.state.outbox.next_try().map(|t| {
+                let wait = Duration::from_secs(t.saturating_sub(clock::now()));
+                wait.max(self.retry.breaker.wait(Instant::now()))
+            });
+            let next = tokio::select! {
+                biased;
                 _ = ct.cancelled() => { return },
-                _ = self.process_events(&raw_client) => ()
+                Some(settings) = self.reloads.recv() => {
+                    self.reload(settings);
+                    continue;
+                }
+                _ = sleep(retry_in.unwrap_or_default()), if retry_in.is_some() => None,
+                next = source.next(&self.client, &mut self.state) => Some(next),
+            };
+            match next {
+                None => self.flush().await,
+                Some(next) => {
+                    if !self.process_events(next).await {
+                        info!("no more events");
+                        return;
+                    }
+                }
             }
         }
     }
 
-    async fn process_events(&mut self, raw_client: &reqwest::Client) {
-        let r = get_events(raw_client, &self.config).await;
-        match r {
-            Err(e) => {
-                self.handle_error(&e).await;
-            }
-            Ok(result) => {
-                self.last_error = false;
-                self.handle_events(&result.events).await;
-                self.handle_config(result).await;
+    /// Replaces routes and templates. The state, with the long poll server
+    /// and unsent messages, stays as it is.
+    fn reload(&mut self, settings: Settings) {
+        self.routes = settings.routes;
+        self.templates = settings.templates;
+        info!("config reloaded");
+    }
+
+    /// Handles the next batch from the source. Returns `false` when the
+    /// source is over.
+    async fn process_events(&mut self, next: SimpleResult<Option<Vec<Event>>>) -> bool {
+        match next {
+            Err(e) => self.handle_error(Operation::Poll, &e).await,
+            Ok(None) => return false,
+            Ok(Some(events)) => {
+                self.retry.success(Operation::Poll);
+                metrics().polled();
+                let ids: Vec<String> = events.iter().map(|_| correlation_id()).collect();
+                for (e, id) in events.iter().zip(&ids) {
+                    metrics().events.with_label_values(&[e.name()]).inc();
+                    info!(
+                        correlation_id = id.as_str(),
+                        event_type = e.name(),
+                        topic_id = e.topic_id();
+                        "got {}", e.name()
+                    );
+                }
+                self.handle_events(&events, &ids).await;
+                self.write_state().await;
             }
         }
+        true
     }
 
-    async fn handle_result<T>(&mut self, r: &SimpleResult<T>) {
-        if let Err(e) = r {
-            self.handle_error(e).await
+    async fn handle_result<T>(&mut self, operation: Operation, r: &SimpleResult<T>) {
+        match r {
+            Ok(_) => self.retry.success(operation),
+            Err(e) => self.handle_error(operation, e).await,
         }
     }
 
-    async fn handle_error(&mut self, e: &Error) {
-        error!("Error: {}", e);
-        let sleep_seconds = if self.last_error { 15 } else { 5 * 60 };
-        self.last_error = true;
-        let ct = self.cancelation.clone();
-        tokio::select! {
-          _ = sleep(Duration::new(sleep_seconds, 0)) => (),
-          _ = ct.cancelled() => () ,
+    async fn handle_error(&mut self, operation: Operation, e: &Error) {
+        let delay = match e.class() {
+            // a lookup only makes the text worse, it's no reason to stop
+            ErrorClass::Fatal if operation != Operation::Lookup => {
+                error!("Fatal error, stopping: {}", e);
+                self.cancelation.cancel();
+                return;
+            }
+            // the source asks again right away, like for a disabled long poll
+            ErrorClass::Rejected if operation == Operation::Poll => {
+                error!("Rejected poll: {}", e);
+                self.retry.failure(operation)
+            }
+            ErrorClass::Fatal | ErrorClass::Rejected => {
+                error!("Rejected: {}", e);
+                return;
+            }
+            ErrorClass::RateLimit => {
+                warn!("Rate limit on {:?}: {}", operation, e);
+                self.retry.failure(operation)
+            }
+            ErrorClass::Retryable => {
+                error!("Error on {:?}: {}", operation, e);
+                self.retry.failure(operation)
+            }
+        };
+        info!("retry {:?} in {:?}", operation, delay);
+        self.pause(delay).await;
+    }
+
+    /// Waits until the delay is over or the bot is stopped. The health check
+    /// keeps passing, as a backoff longer than the stall timeout is no stall.
+    async fn pause(&self, delay: Duration) {
+        let until = Instant::now() + delay;
+        loop {
+            health().beat();
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            tokio::select! {
+                _ = sleep(left.min(BEAT)) => (),
+                _ = self.cancelation.cancelled() => return,
+            }
         }
     }
 
-    async fn handle_config(&mut self, result: Result) {
-        if !result.refresh_all && !result.refresh_key {
-            if let Some(ts) = result.ts {
-                if ts != self.config.ts {
-                    self.config.ts = ts;
-                    self.write_config().await;
+    async fn write_state(&mut self) {
+        write(&self.store, &self.state).await;
+    }
+
+    /// Handles events, `ids` are their correlation ids for the logs.
+    async fn handle_events(&mut self, events: &[Event], ids: &[String]) {
+        let authors = self.authors(events).await;
+        self.resolve_topics(events).await;
+        let mut outgoing: Vec<Outgoing> = vec![];
+        for (event, id) in events.iter().zip(ids) {
+            match event {
+                Event::BoardPost(c) => self.state.board.update(c.topic_id, c.id, c.date),
+                Event::WallPost { id, .. } => {
+                    self.state.last_wall_post = self.state.last_wall_post.max(*id)
                 }
+                _ => (),
             }
+            match event {
+                Event::Message { peer_id, text, .. } => {
+                    self.send_all(take(&mut outgoing)).await;
+                    self.handle_message(*peer_id, text).await
+                }
+                Event::BoardPostEdit(c) | Event::BoardPostRestore(c) => {
+                    self.send_all(take(&mut outgoing)).await;
+                    let edits = self
+                        .state
+                        .sent
+                        .get(&(c.topic_id, c.id))
+                        .iter()
+                        .map(|m| (*m, self.message(m.peer_id, event, &authors)))
+                        .collect();
+                    self.edit_all(edits).await;
+                }
+                Event::BoardPostDelete { topic_id, id } => {
+                    self.send_all(take(&mut outgoing)).await;
+                    let text = format!("[deleted] \n {}", self.board_link(*topic_id, *id));
+                    let edits = self
+                        .state
+                        .sent
+                        .get(&(*topic_id, *id))
+                        .iter()
+                        .map(|m| {
+                            let message = Message {
+                                peer_id: m.peer_id,
+                                text: Some(text.clone()),
+                                attachment: None,
+                                random_id: 0,
+                            };
+                            (*m, message)
+                        })
+                        .collect();
+                    self.edit_all(edits).await;
+                }
+                _ => outgoing.extend(self.forward(event, &authors, id)),
+            }
+        }
+        self.send_all(outgoing).await;
+    }
+
+    /// Resolves titles of new topics, only if some template shows them.
+    async fn resolve_topics(&mut self, events: &[Event]) {
+        if !self.templates.uses(Field::TopicTitle) {
             return;
         }
+        let mut ids: Vec<i64> = events
+            .iter()
+            .filter_map(|e| e.comment().map(|c| c.topic_id))
+            .filter(|id| !self.topics.contains_key(id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.is_empty() {
+            return;
+        }
+        match self.client.get_topics(&ids).await {
+            Err(e) => self.handle_error(Operation::Lookup, &e).await,
+            Ok(topics) => {
+                self.retry.success(Operation::Lookup);
+                self.topics
+                    .extend(topics.into_iter().map(|t| (t.id, t.title)))
+            }
+        }
+    }
 
-        match self.client.long_poll_config().await {
-            Err(e) => self.handle_error(&e).await,
-            Ok(new_config) => {
-                if result.refresh_all {
-                    self.config = new_config
-                } else {
-                    self.config.key = new_config.key
+    /// Resolves all board post authors, with requests only for those
+    /// missing in the cache.
+    async fn authors(&mut self, events: &[Event]) -> HashMap<i64, Author> {
+        let mut ids: Vec<i64> = events
+            .iter()
+            .filter_map(|e| e.comment().map(|c| c.from_id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let now = clock::now();
+        let mut authors = HashMap::new();
+        ids.retain(|id| match self.state.authors.get(*id, now) {
+            Some(author) => {
+                authors.insert(*id, author);
+                false
+            }
+            None => true,
+        });
+        if ids.is_empty() {
+            return authors;
+        }
+        match self.client.get_authors(&ids).await {
+            Err(e) => self.handle_error(Operation::Lookup, &e).await,
+            Ok(resolved) => {
+                self.retry.success(Operation::Lookup);
+                for author in resolved {
+                    self.state.authors.insert(author.clone(), now);
+                    authors.insert(author.id, author);
                 }
+            }
+        }
+        authors
+    }
 
-                if let Some(ts) = result.ts {
-                    self.config.ts = ts
+    fn forward(
+        &mut self,
+        event: &Event,
+        authors: &HashMap<i64, Author>,
+        correlation_id: &str,
+    ) -> Vec<Outgoing> {
+        let key = dedup::key(event);
+        if let Some(key) = key.filter(|k| self.state.seen.contains(k)) {
+            info!(correlation_id; "skip already forwarded {:?}", key);
+            return vec![];
+        }
+        if let Some(key) = key.filter(|k| self.state.outbox.contains(k)) {
+            info!(correlation_id; "skip already queued {:?}", key);
+            return vec![];
+        }
+        let peer_ids = self.destinations(event);
+        if peer_ids.is_empty() {
+            return vec![];
+        }
+        self.forwarded += 1;
+        let post = match event {
+            Event::WallPost { .. } => None,
+            Event::BoardPost(c) => Some((c.topic_id, c.id)),
+            _ => return vec![],
+        };
+        peer_ids
+            .into_iter()
+            .map(|peer_id| {
+                let message = self.message(peer_id, event, authors);
+                Outgoing::new(message, key, post, correlation_id.to_owned())
+            })
+            .collect()
+    }
+
+    /// The message which repeats the event in the chat. Attachments which
+    /// can't be reposted are added to the text as links.
+    fn message(&self, peer_id: i64, event: &Event, authors: &HashMap<i64, Author>) -> Message {
+        let (attachment, links) = match event {
+            Event::WallPost { id, .. } => (Some(format!("wall-{}_{}", self.group_id, id)), vec![]),
+            Event::BoardPost(c) | Event::BoardPostEdit(c) | Event::BoardPostRestore(c) => {
+                attachment::repost(&c.attachments)
+            }
+            _ => (None, vec![]),
+        };
+        let mut text = self.render(peer_id, event, authors);
+        if let Some(text) = text.as_mut().filter(|_| !links.is_empty()) {
+            text.push('\n');
+            text.push_str(&links.join("\n"));
+        }
+        Message {
+            peer_id,
+            text,
+            attachment,
+            random_id: 0,
+        }
+    }
+
+    async fn send_all(&mut self, outgoing: Vec<Outgoing>) {
+        for o in outgoing {
+            self.state.outbox.push(o);
+        }
+        self.flush().await;
+    }
+
+    /// Sends the messages which are due, until every chat is empty or waits
+    /// for a retry.
+    async fn flush(&mut self) {
+        let mut changed = false;
+        loop {
+            let now = clock::now();
+            if self.retry.breaker.state(Instant::now()) == BreakerState::Open {
+                break;
+            }
+            let due = self.state.outbox.due(now);
+            if due.is_empty() || self.cancelation.is_cancelled() {
+                break;
+            }
+            let messages: Vec<Message> = due
+                .iter()
+                .filter_map(|i| self.state.outbox.get(*i))
+                .map(|o| o.message.clone())
+                .collect();
+            let results = self.client.send_messages(&messages).await;
+            changed = true;
+            let mut done = vec![];
+            let mut failed = vec![];
+            let mut retry = false;
+            // handled after the batch, so its sent messages are not repeated
+            let mut fatal = None;
+            for (i, r) in due.into_iter().zip(results) {
+                if let Some(o) = self.state.outbox.get(i) {
+                    let peer_id = o.message.peer_id.to_string();
+                    let counter = if r.is_ok() {
+                        info!(
+                            correlation_id = o.correlation_id.as_str(),
+                            peer_id = o.message.peer_id,
+                            method = "messages.send";
+                            "sent to {}", o.message.peer_id
+                        );
+                        &metrics().sent
+                    } else {
+                        &metrics().failed
+                    };
+                    counter.with_label_values(&[&peer_id]).inc();
+                }
+                match r {
+                    Ok(conversation_message_id) => done.push((i, Some(conversation_message_id))),
+                    Err(e) => match e.class() {
+                        ErrorClass::Fatal => fatal = Some(e),
+                        ErrorClass::Rejected => failed.push((i, e)),
+                        ErrorClass::Retryable | ErrorClass::RateLimit => {
+                            retry = true;
+                            match self.state.outbox.get_mut(i) {
+                                Some(o) if o.attempts + 1 < self.outbox_attempts => {
+                                    warn!(
+                                        correlation_id = o.correlation_id.as_str(),
+                                        peer_id = o.message.peer_id;
+                                        "retry sending to {}: {}", o.message.peer_id, e
+                                    );
+                                    let delay = self.retry.delay(Operation::Send, o.attempts);
+                                    o.attempts += 1;
+                                    o.next_try = now + delay.as_secs().max(1);
+                                }
+                                _ => failed.push((i, e)),
+                            }
+                        }
+                    },
+                }
+            }
+            if retry {
+                self.retry.breaker.failure(Instant::now());
+            }
+            for (i, e) in &failed {
+                if let Some(o) = self.state.outbox.get(*i) {
+                    dead_letter(self.dead_letter.as_deref(), o, e, now).await;
+                }
+                done.push((*i, None));
+            }
+            done.sort_unstable();
+            let indexes: Vec<usize> = done.iter().map(|(i, _)| *i).collect();
+            let removed = self.state.outbox.remove(&indexes);
+            for (o, (_, id)) in removed.into_iter().zip(done) {
+                if let Some(conversation_message_id) = id {
+                    self.sent(o, conversation_message_id);
                 }
-                self.write_config().await;
+            }
+            if let Some(e) = fatal {
+                self.handle_error(Operation::Send, &e).await;
+                break;
+            }
+            if !retry {
+                self.retry.success(Operation::Send);
             }
         }
+        metrics().queued.set(self.state.outbox.len() as i64);
+        // saved at once, so a crash doesn't repeat the posts after restart
+        if changed {
+            self.write_state().await;
+        }
     }
 
-    async fn write_config(&mut self) {
-        write(&mut self.config_provider, &self.config).await;
+    fn sent(&mut self, o: Outgoing, conversation_message_id: i64) {
+        if let Some(key) = o.event {
+            self.state.seen.insert(key);
+        }
+        if let Some(key) = o.post {
+            self.state.sent.insert(
+                key,
+                SentMessage {
+                    peer_id: o.message.peer_id,
+                    conversation_message_id,
+                },
+            )
+        }
     }
 
-    async fn handle_events(&mut self, events: &[Event]) {
-        for event in events {
-            match event {
-                Event::WallPost { id } => {
-                    let attachment = format!("wall-{}_{}", self.group_id, id);
-                    let r = self
-                        .client
-                        .send_message(self.chat_id, None, Some(attachment))
-                        .await;
-                    self.handle_result(&r).await;
+    async fn edit_all(&mut self, edits: Vec<(SentMessage, Message)>) {
+        for (m, message) in edits {
+            if let Some(text) = message.text {
+                let r = self
+                    .client
+                    .edit_message(
+                        m.peer_id,
+                        m.conversation_message_id,
+                        text,
+                        message.attachment,
+                    )
+                    .await;
+                match r {
+                    // an old message may be out of reach, new ones still go
+                    Err(e) if e.class() == ErrorClass::Fatal => {
+                        error!("can't edit message in {}: {}", m.peer_id, e)
+                    }
+                    r => self.handle_result(Operation::Send, &r).await,
                 }
-                Event::BoardPost {
-                    from_id,
+            }
+        }
+    }
+
+    /// Renders the event text with the template of the chat.
+    fn render(
+        &self,
+        peer_id: i64,
+        event: &Event,
+        authors: &HashMap<i64, Author>,
+    ) -> Option<String> {
+        let template = self.templates.get(peer_id, event.kind())?;
+        let text_length = self.templates.text_length(peer_id);
+        let text = match event {
+            Event::BoardPost(c) | Event::BoardPostEdit(c) | Event::BoardPostRestore(c) => {
+                let author = match authors.get(&c.from_id) {
+                    Some(author) => author.clone(),
+                    None => Author::unknown(c.from_id),
+                };
+                let topic_title = self.topics.get(&c.topic_id);
+                let values = Values {
+                    author: &author.name,
+                    author_link: &author.link,
+                    text: &c.text,
+                    topic_title: topic_title.map(|t| t.as_str()).unwrap_or(""),
+                    link: &self.board_link(c.topic_id, c.id),
+                    date: c.date,
+                };
+                template.render(&values, text_length)
+            }
+            Event::WallPost { id, text, date } => {
+                let values = Values {
                     text,
-                    topic_id,
-                    id,
-                } => {
-                    let text = text.chars().take(100).collect::<String>();
-                    let user = self.client.get_user(*from_id).await;
-                    let user_name = match user {
-                        Err(e) => {
-                            self.handle_error(&e).await;
-                            String::new()
-                        }
-                        Ok(user) => format!(
-                            "{} {}",
-                            user.first_name.unwrap_or(String::new()),
-                            user.last_name.unwrap_or(String::new())
-                        ),
-                    };
-                    let message = format!(
-                        "{}: {} \n https://vk.com/topic-{}_{}?post={}",
-                        user_name, text, self.group_id, topic_id, id
-                    );
-                    let r = self
-                        .client
-                        .send_message(self.chat_id, Some(message), None)
-                        .await;
-                    self.handle_result(&r).await;
+                    link: &format!("https://vk.com/wall-{}_{}", self.group_id, id),
+                    date: *date,
+                    ..Values::default()
+                };
+                template.render(&values, text_length)
+            }
+            _ => return None,
+        };
+        Some(text)
+    }
+
+    fn board_link(&self, topic_id: i64, id: i64) -> String {
+        format!(
+            "https://vk.com/topic-{}_{}?post={}",
+            self.group_id, topic_id, id
+        )
+    }
+
+    fn destinations(&self, event: &Event) -> Vec<i64> {
+        let mut peer_ids = self.routes.destinations(event);
+        if let Event::BoardPost(c) = event {
+            peer_ids.retain(|p| match self.state.muted.get(p) {
+                Some(topics) => !topics.contains(&c.topic_id),
+                None => true,
+            });
+        }
+        peer_ids
+    }
+
+    async fn handle_message(&mut self, peer_id: i64, text: &str) {
+        let reply = match commands::parse(text) {
+            None => return,
+            Some(Err(e)) => e,
+            Some(Ok(command)) => {
+                info!("command {:?} from {}", command, peer_id);
+                self.handle_command(peer_id, command)
+            }
+        };
+        let r = self.client.send_message(peer_id, Some(reply), None).await;
+        self.handle_result(Operation::Send, &r).await;
+    }
+
+    fn handle_command(&mut self, peer_id: i64, command: Command) -> String {
+        match command {
+            Command::Help => commands::HELP.to_string(),
+            Command::Status => {
+                let mut muted: Vec<i64> = self
+                    .state
+                    .muted
+                    .get(&peer_id)
+                    .map(|t| t.iter().copied().collect())
+                    .unwrap_or_default();
+                muted.sort_unstable();
+                let muted: Vec<String> = muted.iter().map(|id| id.to_string()).collect();
+                format!(
+                    "up {} s, forwarded {} events, {} queued, circuit {}, ts {}, muted topics: {}",
+                    self.started.elapsed().as_secs(),
+                    self.forwarded,
+                    self.state.outbox.len(),
+                    self.retry.breaker.state(Instant::now()),
+                    self.state
+                        .server
+                        .as_ref()
+                        .map(|s| s.ts.as_str())
+                        .unwrap_or("-"),
+                    if muted.is_empty() {
+                        "none".to_string()
+                    } else {
+                        muted.join(", ")
+                    }
+                )
+            }
+            Command::Mute(topic_id) => {
+                self.state
+                    .muted
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(topic_id);
+                format!("topic {} muted", topic_id)
+            }
+            Command::Unmute(topic_id) => {
+                if let Some(topics) = self.state.muted.get_mut(&peer_id) {
+                    topics.remove(&topic_id);
                 }
+                format!("topic {} unmuted", topic_id)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event_source::{LongPoll, Replay};
+    use crate::retry::{CircuitBreaker, RetryPolicy};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc::channel;
+    use tokio::time::timeout;
+
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    const SENT: &str = r#"{"response":[[{"peer_id":2000000001,"conversation_message_id":7}]]}"#;
+
+    const UPDATE: &str = r#"{"ts":"4","updates":[{"type":"board_post_new","object":{"from_id":1000,"text":"some text","id":123,"topic_id":456},"group_id":123456}]}"#;
+
+    /// Answers `users.get` and `execute` like VK and records every request.
+    fn fake_api(requests: Requests, execute: &'static str) -> String {
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        let method = req.uri().path().trim_start_matches('/').to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = String::from_utf8_lossy(&body).to_string();
+                        requests.lock().unwrap().push((method.clone(), body));
+                        let response = match method.as_str() {
+                            "users.get" => {
+                                r#"{"response":[{"id":1000,"first_name":"Ivan","last_name":"Petrov"}]}"#
+                            }
+                            "execute" => execute,
+                            "messages.edit" => r#"{"response":1}"#,
+                            "groups.getLongPollServer" => {
+                                r#"{"error":{"error_code":100,"error_msg":"Long Poll is disabled"}}"#
+                            }
+                            _ => r#"{"error":{"error_code":3,"error_msg":"unknown method"}}"#,
+                        };
+                        Ok::<_, Infallible>(Response::new(Body::from(response)))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn test_retry() -> Retry {
+        let policy = RetryPolicy {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        };
+        Retry::new(
+            policy,
+            policy,
+            policy,
+            CircuitBreaker::new(5, Duration::from_millis(100)),
+        )
+    }
+
+    fn worker(client: Client) -> Worker {
+        let mut state = State::new(None);
+        state.authors.limit(10, 3600);
+        Worker {
+            group_id: 123456,
+            routes: RoutingTable::single(2000000001),
+            client,
+            state,
+            store: None,
+            retry: test_retry(),
+            outbox_attempts: 3,
+            dead_letter: None,
+            cancelation: CancellationToken::new(),
+            forwarded: 0,
+            started: Instant::now(),
+            templates: Templates::default(),
+            topics: HashMap::new(),
+            reloads: channel(1).1,
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_board_post() {
+        let requests = Requests::default();
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests.clone(), SENT));
+        // the same post delivered again, like after a restart
+        let mut replay = Replay::parse(&format!("{}\n{}", UPDATE, UPDATE)).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        let requests = requests.lock().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|(m, _)| m.as_str()).collect();
+        assert_eq!(vec!["users.get", "execute"], methods);
+        assert!(requests[1].1.contains("Ivan+Petrov%3A+some+text"));
+        assert_eq!(
+            &[SentMessage {
+                peer_id: 2000000001,
+                conversation_message_id: 7
+            }],
+            w.state.sent.get(&(456, 123))
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_failed_message() {
+        let requests = Requests::default();
+        let failed = r#"{"response":[false],"execute_errors":[{"method":"messages.send","error_code":10,"error_msg":"Internal server error"}]}"#;
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests, failed));
+        let mut replay = Replay::parse(UPDATE).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        assert_eq!(1, w.state.outbox.len());
+        let o = w.state.outbox.get(0).unwrap();
+        assert_eq!(1, o.attempts);
+        assert!(o.next_try > 0);
+        assert!(w.state.sent.get(&(456, 123)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_queued() {
+        let requests = Requests::default();
+        let failed = r#"{"response":[false],"execute_errors":[{"method":"messages.send","error_code":10,"error_msg":"Internal server error"}]}"#;
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests, failed));
+        let mut replay = Replay::parse(&format!("{}\n{}", UPDATE, UPDATE)).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        assert_eq!(1, w.state.outbox.len());
+    }
+
+    #[tokio::test]
+    async fn keep_random_id() {
+        let requests = Requests::default();
+        let failed = r#"{"response":[false],"execute_errors":[{"method":"messages.send","error_code":10,"error_msg":"Internal server error"}]}"#;
+        let client = Client::new("token".to_owned(), 123456, 100)
+            .with_url(fake_api(requests.clone(), failed));
+        let mut replay = Replay::parse(UPDATE).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+        w.state.outbox.get_mut(0).unwrap().next_try = 0;
+        w.flush().await;
+
+        let random_id = w.state.outbox.get(0).unwrap().message.random_id;
+        let param = format!("%22random_id%22%3A%22{}%22", random_id);
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert!(requests[1].1.contains(&param));
+        assert!(requests[2].1.contains(&param));
+    }
+
+    #[tokio::test]
+    async fn stop_after_batch() {
+        let requests = Requests::default();
+        let fatal = r#"{"response":[[{"peer_id":2000000001,"conversation_message_id":7}],false],"execute_errors":[{"method":"messages.send","error_code":5,"error_msg":"User authorization failed"}]}"#;
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests, fatal));
+        let mut replay = Replay::parse(UPDATE).unwrap();
+        let mut w = worker(client);
+        w.routes =
+            serde_json::from_str(r#"{"routes":[{"peer_ids":[2000000001,2000000002]}]}"#).unwrap();
+        w.main_loop(&mut replay).await;
+
+        assert!(w.cancelation.is_cancelled());
+        assert_eq!(1, w.state.sent.get(&(456, 123)).len());
+        assert_eq!(1, w.state.outbox.len());
+        assert_eq!(2000000002, w.state.outbox.get(0).unwrap().message.peer_id);
+    }
+
+    #[tokio::test]
+    async fn reject_chat() {
+        let requests = Requests::default();
+        let removed = r#"{"response":[[{"peer_id":2000000001,"error":{"code":917,"description":"You don't have access to this chat"}}]]}"#;
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests, removed));
+        let mut replay = Replay::parse(UPDATE).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        assert!(!w.cancelation.is_cancelled());
+        assert_eq!(0, w.state.outbox.len());
+        assert!(w.state.sent.get(&(456, 123)).is_empty());
+        assert_eq!(BreakerState::Closed, w.retry.breaker.state(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn reload_routes() {
+        let requests = Requests::default();
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests.clone(), SENT));
+        let mut replay = Replay::parse(UPDATE).unwrap();
+        let mut w = worker(client);
+        let (tx, rx) = channel(1);
+        w.reloads = rx;
+        let settings = Settings {
+            routes: RoutingTable::single(2000000002),
+            templates: Templates::default(),
+        };
+        tx.send(settings).await.unwrap();
+        w.main_loop(&mut replay).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!("execute", requests[1].0);
+        assert!(requests[1].1.contains("2000000002"));
+        assert!(!requests[1].1.contains("2000000001"));
+    }
+
+    #[tokio::test]
+    async fn forward_attachments() {
+        let requests = Requests::default();
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests.clone(), SENT));
+        let update = r#"{"ts":"4","updates":[{"type":"board_post_new","object":{"from_id":1000,"text":"some text","id":123,"topic_id":456,"attachments":[{"type":"photo","photo":{"id":1,"owner_id":-2,"access_key":"k"}},{"type":"link","link":{"url":"https://example.com"}}]},"group_id":123456}]}"#;
+        let mut replay = Replay::parse(update).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!("execute", requests[1].0);
+        assert!(requests[1].1.contains("photo-2_1_k"));
+        assert!(requests[1].1.contains("https%3A%2F%2Fexample.com"));
+    }
+
+    #[tokio::test]
+    async fn edit_with_attachments() {
+        let requests = Requests::default();
+        let client =
+            Client::new("token".to_owned(), 123456, 100).with_url(fake_api(requests.clone(), SENT));
+        let edit = r#"{"ts":"5","updates":[{"type":"board_post_edit","object":{"from_id":1000,"text":"new text","id":123,"topic_id":456,"attachments":[{"type":"photo","photo":{"id":1,"owner_id":-2,"access_key":"k"}},{"type":"link","link":{"url":"https://example.com"}}]},"group_id":123456}]}"#;
+        let mut replay = Replay::parse(&format!("{}\n{}", UPDATE, edit)).unwrap();
+        let mut w = worker(client);
+        w.main_loop(&mut replay).await;
+
+        let requests = requests.lock().unwrap();
+        let (method, body) = requests.last().unwrap();
+        assert_eq!("messages.edit", method);
+        assert!(body.contains("attachment=photo-2_1_k"));
+        assert!(body.contains("new+text"));
+        assert!(body.contains("https%3A%2F%2Fexample.com"));
+    }
+
+    #[tokio::test]
+    async fn back_off_rejected_poll() {
+        let requests = Requests::default();
+        let client = Client::new("token".to_owned(), 123456, 1000)
+            .with_url(fake_api(requests.clone(), SENT));
+        let mut source = LongPoll::new(&client);
+        let mut w = worker(client);
+        let r = timeout(Duration::from_millis(300), w.main_loop(&mut source)).await;
+        assert!(r.is_err());
+
+        let requests = requests.lock().unwrap();
+        let polls = requests
+            .iter()
+            .filter(|(m, _)| m == "groups.getLongPollServer")
+            .count();
+        assert!(polls > 1);
+        assert!(polls < 20, "{} requests", polls);
+    }
+}